@@ -0,0 +1,334 @@
+//! Command line arguments
+
+use std::{path::PathBuf, time::Duration};
+use xscreensaver_suspend::{
+    config::{self, parse_duration, Config},
+    toml::{self, Value},
+};
+
+pub const USAGE: &str = "\
+Usage: xscreensaver-suspend [OPTIONS]
+       xscreensaver-suspend inhibit [--for DURATION] [--until-pid PID] [--reason TEXT]
+       xscreensaver-suspend inhibit [--for DURATION] [--reason TEXT] -- COMMAND [ARG...]
+       xscreensaver-suspend uninhibit [ID...]
+       xscreensaver-suspend list
+       xscreensaver-suspend status [--json]
+       xscreensaver-suspend suspend-now|reload|pause|resume
+
+Sleep when xscreensaver has locked the screen for as long as its dpmsOff time.
+
+Commands:
+  inhibit                             Don't sleep until uninhibited, or for DURATION, e.g. 2h.
+                                      Prints an ID for uninhibit. With --until-pid, only
+                                      while process PID runs. With a COMMAND, runs it and
+                                      inhibits until it exits, exiting with its status
+  uninhibit                           Stop inhibiting, for every numbered ID if none are given
+  list                                Show what's stopping sleep
+  status                              Show what the daemon is doing and will do next,
+                                      as JSON with --json
+  suspend-now                         Sleep now, if the screen is locked
+  reload                              Reload xscreensaver's settings
+  pause, resume                       Stop sleeping until resumed, and start again
+
+Options override the config file, $XDG_CONFIG_HOME/xscreensaver-suspend/config.toml:
+  --config PATH                       Use a different config file
+  --action ACTION                     suspend, hibernate, hybrid-sleep, suspend-then-hibernate,
+                                      poweroff, mem, disk, freeze, standby, or a command
+                                      as an array such as '[\"/usr/sbin/pm-suspend\"]'
+  --backend BACKEND                   logind or systemctl
+  --no-suspend DURATION               How long `touch ~/.no_suspend` blocks sleeping, e.g. 8h
+  --password-timeout-multiplier N     Multiples of passwdTimeout before sleeping again
+  --poll-interval DURATION            How often to check the timers while locked, e.g. 30s
+  --systemctl PATH                    systemctl binary
+  --xscreensaver-command PATH         xscreensaver-command binary
+  --xrdb PATH                         xrdb binary, to read settings loaded into X resources
+  --hooks DIR                         Where the pre-suspend.d and post-resume.d hooks are
+  --hook-timeout DURATION             How long a hook can run before it's killed, e.g. 30s
+  --grace-period DURATION             Warn with a notification this long before sleeping
+  --cancel-inhibit DURATION           How long the notification's Cancel stops sleeping for
+  --fallback ACTION                   What to try if ACTION doesn't put the system to sleep
+  --verify-timeout DURATION           How long to wait for the system to sleep, e.g. 30s
+  --retry-backoff DURATION            How long to wait after failing to sleep, doubling each time
+  --sysfs PATH                        Where sysfs is mounted, /sys by default
+  --escalate-after-sleeps N           Switch to --escalate-action after sleeping N times
+  --escalate-after DURATION           Switch to --escalate-action after being locked this long
+  --escalate-action ACTION            What to do instead, hibernate by default
+  -h, --help                          Show this help
+";
+
+/// What to do
+#[derive(Debug, Default, PartialEq)]
+pub enum Command {
+    /// Run the daemon
+    #[default]
+    Run,
+    Inhibit {
+        /// How long for, or until uninhibited if None
+        duration: Option<Duration>,
+        /// Only while this process runs
+        pid: Option<u32>,
+        /// Run this, and only inhibit while it runs
+        command: Vec<String>,
+        reason: String,
+    },
+    /// Remove these inhibits, or all of them if there are none
+    Uninhibit(Vec<String>),
+    List,
+    Status {
+        json: bool,
+    },
+    SuspendNow,
+    Reload,
+    Pause,
+    Resume,
+}
+
+/// Parsed command line
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    /// Just print the usage
+    pub help: bool,
+    pub command: Command,
+    /// Config file to use instead of the default
+    pub config: Option<PathBuf>,
+    /// Settings given as flags, with the flag they came from
+    pub overrides: Vec<(String, &'static str, Value)>,
+}
+
+impl Args {
+    /// Parse the arguments, not including the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" {
+                parsed.help = true;
+                continue;
+            }
+            if arg == "--" {
+                match &mut parsed.command {
+                    Command::Inhibit { command, .. } => command.extend(args.by_ref()),
+                    _ => return Err("only inhibit runs a command after --".into()),
+                }
+                continue;
+            }
+            let Some(flag) = arg.strip_prefix("--") else {
+                match (&mut parsed.command, arg.as_str()) {
+                    (Command::Run, "inhibit") => {
+                        parsed.command = Command::Inhibit {
+                            duration: None,
+                            pid: None,
+                            command: Vec::new(),
+                            reason: String::new(),
+                        }
+                    }
+                    (Command::Run, "uninhibit") => parsed.command = Command::Uninhibit(Vec::new()),
+                    (Command::Run, "list") => parsed.command = Command::List,
+                    (Command::Run, "status") => parsed.command = Command::Status { json: false },
+                    (Command::Run, "suspend-now") => parsed.command = Command::SuspendNow,
+                    (Command::Run, "reload") => parsed.command = Command::Reload,
+                    (Command::Run, "pause") => parsed.command = Command::Pause,
+                    (Command::Run, "resume") => parsed.command = Command::Resume,
+                    (Command::Uninhibit(ids), _) => ids.push(arg),
+                    _ => return Err(format!("unexpected argument {arg:?}")),
+                }
+                continue;
+            };
+            if let (Command::Status { json }, "json") = (&mut parsed.command, flag) {
+                *json = true;
+                continue;
+            }
+            let (flag, value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag, value.to_string()),
+                None => (
+                    flag,
+                    args.next()
+                        .ok_or_else(|| format!("--{flag} needs a value"))?,
+                ),
+            };
+            match (&mut parsed.command, flag) {
+                (_, "config") => {
+                    parsed.config = Some(value.into());
+                    continue;
+                }
+                (Command::Inhibit { duration, .. }, "for") => {
+                    *duration = Some(parse_duration(&value)?);
+                    continue;
+                }
+                (Command::Inhibit { pid, .. }, "until-pid") => {
+                    let id = value
+                        .parse()
+                        .map_err(|_| format!("{value:?} isn't a PID"))?;
+                    *pid = Some(id);
+                    continue;
+                }
+                (Command::Inhibit { reason, .. }, "reason") => {
+                    *reason = value;
+                    continue;
+                }
+                _ => {}
+            }
+            let key = config::KEYS
+                .iter()
+                .find(|key| key.replace(['_', '.'], "-") == flag)
+                .ok_or_else(|| format!("unknown option --{flag}"))?;
+            // A typed value that isn't a TOML number, string or array, such as `suspend`, is
+            // taken as a string too
+            let value = match config::TYPED_KEYS.contains(key) {
+                true => toml::parse_value(&value).unwrap_or(Value::String(value)),
+                false => Value::String(value),
+            };
+            parsed.overrides.push((format!("--{flag}"), key, value));
+        }
+        if let Command::Inhibit { pid, command, .. } = &parsed.command {
+            if pid.is_some() && !command.is_empty() {
+                return Err("--until-pid and a command can't be used together".into());
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Override settings from the config file
+    pub fn apply(&self, config: &mut Config) -> Result<(), config::Error> {
+        for (flag, key, value) in &self.overrides {
+            config
+                .set(key, value)
+                .map_err(|message| config::Error::Flag(flag.clone(), message))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xscreensaver_suspend::sleep::SleepAction;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse(&[]).unwrap(), Args::default());
+        assert!(parse(&["--help"]).unwrap().help);
+        assert_eq!(
+            parse(&[
+                "inhibit",
+                "--for",
+                "2h",
+                "--reason=render",
+                "--",
+                "make",
+                "-j4"
+            ])
+            .unwrap()
+            .command,
+            Command::Inhibit {
+                duration: Some(Duration::from_secs(2 * 60 * 60)),
+                pid: None,
+                command: vec!["make".into(), "-j4".into()],
+                reason: "render".into(),
+            }
+        );
+        assert_eq!(
+            parse(&["uninhibit", "1", "backup"]).unwrap().command,
+            Command::Uninhibit(vec!["1".into(), "backup".into()])
+        );
+        assert_eq!(
+            parse(&["status", "--json"]).unwrap().command,
+            Command::Status { json: true }
+        );
+        assert_eq!(
+            parse(&["--config", "/tmp/c.toml", "suspend-now"]).unwrap(),
+            Args {
+                command: Command::SuspendNow,
+                config: Some("/tmp/c.toml".into()),
+                ..Args::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let error = |args: &[&str]| parse(args).unwrap_err();
+        assert_eq!(error(&["nap"]), "unexpected argument \"nap\"");
+        assert_eq!(error(&["list", "extra"]), "unexpected argument \"extra\"");
+        assert_eq!(error(&["--colour", "red"]), "unknown option --colour");
+        assert_eq!(error(&["--action"]), "--action needs a value");
+        assert_eq!(error(&["--", "ls"]), "only inhibit runs a command after --");
+        assert_eq!(
+            error(&["inhibit", "--until-pid", "me"]),
+            "\"me\" isn't a PID"
+        );
+        assert_eq!(
+            error(&["inhibit", "--until-pid", "1", "--", "ls"]),
+            "--until-pid and a command can't be used together"
+        );
+        // Inhibit's own flags only go with inhibit
+        assert_eq!(error(&["--for", "2h"]), "unknown option --for");
+    }
+
+    #[test]
+    fn overrides_the_config() {
+        let args = parse(&[
+            "--action",
+            "[\"/usr/sbin/pm-suspend\"]",
+            "--password-timeout-multiplier=2",
+            "--poll-interval",
+            "10s",
+            "--escalate-after-sleeps",
+            "3",
+            "--escalate-action",
+            "hibernate",
+        ])
+        .unwrap();
+        let mut config = Config::default();
+        args.apply(&mut config).unwrap();
+        assert_eq!(
+            config.action,
+            SleepAction::Command(vec!["/usr/sbin/pm-suspend".into()])
+        );
+        assert_eq!(config.password_timeout_multiplier, 2);
+        assert_eq!(config.poll_interval, Duration::from_secs(10));
+        let escalate = config.escalate.unwrap();
+        assert_eq!(escalate.after_sleeps, Some(3));
+        assert_eq!(escalate.action, SleepAction::Hibernate);
+
+        // Errors say which flag was wrong
+        let error = parse(&["--hook-timeout", "soon"])
+            .unwrap()
+            .apply(&mut Config::default())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--hook-timeout: invalid duration \"soon\", expected something like 90s, 15m, \
+             1h30m or 2d"
+        );
+        let error = parse(&["--password-timeout-multiplier", "three"])
+            .unwrap()
+            .apply(&mut Config::default())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--password-timeout-multiplier: password_timeout_multiplier should be a positive \
+             integer"
+        );
+    }
+
+    #[test]
+    fn takes_strings_as_they_are() {
+        let args = parse(&["--systemctl", "/opt/a #b", "--xrdb=\"quoted\""]).unwrap();
+        assert_eq!(
+            args.overrides,
+            vec![
+                (
+                    "--systemctl".into(),
+                    "systemctl",
+                    Value::String("/opt/a #b".into())
+                ),
+                ("--xrdb".into(), "xrdb", Value::String("\"quoted\"".into())),
+            ]
+        );
+    }
+}
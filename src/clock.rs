@@ -0,0 +1,254 @@
+//! Time that keeps counting while the machine is asleep
+
+use crate::sys::{self, CLOCK_BOOTTIME, CLOCK_MONOTONIC};
+use std::{
+    fmt,
+    ops::Add,
+    time::{Duration, SystemTime},
+};
+
+/// Wall clock changes smaller than this are just drift between the clocks
+const JUMP_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// A point in time on CLOCK_BOOTTIME, which unlike `Instant` includes time spent asleep
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BootInstant(Duration);
+
+impl BootInstant {
+    /// Time from `earlier` to this, or zero if `earlier` is later
+    pub fn duration_since(self, earlier: BootInstant) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+}
+
+/// Saturates rather than overflowing, so a huge timeout is just never reached
+impl Add<Duration> for BootInstant {
+    type Output = BootInstant;
+
+    fn add(self, duration: Duration) -> BootInstant {
+        BootInstant(self.0.saturating_add(duration))
+    }
+}
+
+/// Where the time comes from
+pub trait Clock {
+    /// CLOCK_BOOTTIME
+    fn now(&self) -> BootInstant;
+    /// CLOCK_MONOTONIC, which stops while asleep
+    fn monotonic(&self) -> Duration;
+    /// The wall clock, which can be changed at any time
+    fn wall(&self) -> SystemTime;
+
+    /// How long the system has spent asleep since booting
+    fn asleep(&self) -> Duration {
+        self.now().0.saturating_sub(self.monotonic())
+    }
+}
+
+/// The kernel's clocks
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> BootInstant {
+        // Kernels before 2.6.39 don't have CLOCK_BOOTTIME, so sleeps go unnoticed
+        BootInstant(sys::clock(CLOCK_BOOTTIME).unwrap_or_else(|_| self.monotonic()))
+    }
+
+    fn monotonic(&self) -> Duration {
+        // Always there
+        sys::clock(CLOCK_MONOTONIC).unwrap_or_default()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// What happened between two readings of a `ClockWatch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Elapsed {
+    /// Real time that passed, including time asleep
+    pub real: Duration,
+    /// How much of that the system spent asleep
+    pub asleep: Duration,
+    /// Whether the wall clock was changed
+    pub wall_jump: Option<WallJump>,
+}
+
+/// A change to the wall clock, on top of the time that really passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WallJump {
+    Forward(Duration),
+    Backward(Duration),
+}
+
+impl fmt::Display for WallJump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WallJump::Forward(by) => write!(f, "forward {}s", by.as_secs()),
+            WallJump::Backward(by) => write!(f, "back {}s", by.as_secs()),
+        }
+    }
+}
+
+/// Notices sleeps and wall clock changes by comparing the clocks between readings
+#[derive(Debug)]
+pub struct ClockWatch<C> {
+    clock: C,
+    boot: BootInstant,
+    monotonic: Duration,
+    wall: SystemTime,
+}
+
+impl<C: Clock> ClockWatch<C> {
+    pub fn new(clock: C) -> Self {
+        ClockWatch {
+            boot: clock.now(),
+            monotonic: clock.monotonic(),
+            wall: clock.wall(),
+            clock,
+        }
+    }
+
+    pub fn now(&self) -> BootInstant {
+        self.clock.now()
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Read the clocks, and work out what happened since the last time
+    pub fn tick(&mut self) -> Elapsed {
+        let (boot, monotonic, wall) = (self.clock.now(), self.clock.monotonic(), self.clock.wall());
+        let real = boot.duration_since(self.boot);
+        let asleep = real.saturating_sub(monotonic.saturating_sub(self.monotonic));
+        let wall_jump = match wall.duration_since(self.wall) {
+            Ok(moved) if moved > real + JUMP_TOLERANCE => Some(WallJump::Forward(moved - real)),
+            Ok(moved) => real
+                .checked_sub(moved)
+                .filter(|back| *back > JUMP_TOLERANCE)
+                .map(WallJump::Backward),
+            Err(back) => Some(real + back.duration())
+                .filter(|back| *back > JUMP_TOLERANCE)
+                .map(WallJump::Backward),
+        };
+        (self.boot, self.monotonic, self.wall) = (boot, monotonic, wall);
+        Elapsed {
+            real,
+            asleep,
+            wall_jump,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_clock {
+    use super::*;
+    use std::{cell::Cell, rc::Rc};
+
+    /// A clock that only moves when told to
+    #[derive(Debug, Clone)]
+    pub struct TestClock {
+        boot: Rc<Cell<Duration>>,
+        monotonic: Rc<Cell<Duration>>,
+        wall: Rc<Cell<SystemTime>>,
+    }
+
+    impl TestClock {
+        pub fn new() -> Self {
+            TestClock {
+                boot: Rc::new(Cell::new(Duration::from_secs(100))),
+                monotonic: Rc::new(Cell::new(Duration::from_secs(100))),
+                wall: Rc::new(Cell::new(
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000),
+                )),
+            }
+        }
+
+        /// Let time pass while awake
+        pub fn advance(&self, by: Duration) {
+            self.boot.set(self.boot.get() + by);
+            self.monotonic.set(self.monotonic.get() + by);
+            self.wall.set(self.wall.get() + by);
+        }
+
+        /// Let time pass while asleep
+        pub fn sleep(&self, by: Duration) {
+            self.boot.set(self.boot.get() + by);
+            self.wall.set(self.wall.get() + by);
+        }
+
+        pub fn set_wall(&self, wall: SystemTime) {
+            self.wall.set(wall);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> BootInstant {
+            BootInstant(self.boot.get())
+        }
+
+        fn monotonic(&self) -> Duration {
+            self.monotonic.get()
+        }
+
+        fn wall(&self) -> SystemTime {
+            self.wall.get()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_clock::TestClock;
+
+    #[test]
+    fn counts_time_asleep() {
+        let clock = TestClock::new();
+        let mut watch = ClockWatch::new(clock.clone());
+        clock.advance(Duration::from_secs(5));
+        clock.sleep(Duration::from_secs(3600));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            watch.tick(),
+            Elapsed {
+                real: Duration::from_secs(3610),
+                asleep: Duration::from_secs(3600),
+                wall_jump: None,
+            }
+        );
+        assert_eq!(watch.tick(), Elapsed::default());
+    }
+
+    #[test]
+    fn notices_wall_clock_jumps() {
+        let clock = TestClock::new();
+        let mut watch = ClockWatch::new(clock.clone());
+        let wall = clock.wall();
+        clock.advance(Duration::from_secs(10));
+
+        clock.set_wall(wall + Duration::from_secs(70));
+        assert_eq!(
+            watch.tick().wall_jump,
+            Some(WallJump::Forward(Duration::from_secs(60)))
+        );
+
+        clock.advance(Duration::from_secs(10));
+        clock.set_wall(wall);
+        assert_eq!(
+            watch.tick().wall_jump,
+            Some(WallJump::Backward(Duration::from_secs(80)))
+        );
+
+        clock.advance(Duration::from_secs(10));
+        clock.set_wall(wall + Duration::from_secs(9));
+        assert_eq!(
+            watch.tick().wall_jump,
+            None,
+            "drift within the tolerance isn't a jump"
+        );
+    }
+}
@@ -0,0 +1,663 @@
+//! Our own settings, from `$XDG_CONFIG_HOME/xscreensaver-suspend/config.toml`
+
+use crate::{
+    sleep::{Backend, SleepAction},
+    toml::{self, Value},
+};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Every setting, as written in the config file
+pub const KEYS: [&str; 19] = [
+    "action",
+    "backend",
+    "no_suspend",
+    "password_timeout_multiplier",
+    "poll_interval",
+    "systemctl",
+    "xscreensaver_command",
+    "xrdb",
+    "hooks",
+    "hook_timeout",
+    "grace_period",
+    "cancel_inhibit",
+    "fallback",
+    "verify_timeout",
+    "retry_backoff",
+    "sysfs",
+    "escalate.after_sleeps",
+    "escalate.after",
+    "escalate.action",
+];
+
+/// Settings whose flags are TOML values: numbers, and actions that can be commands as arrays.
+/// The others' flags are strings taken as they are.
+pub const TYPED_KEYS: [&str; 5] = [
+    "action",
+    "password_timeout_multiplier",
+    "fallback",
+    "escalate.after_sleeps",
+    "escalate.action",
+];
+
+/// Settings for the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// What to do once the screen has been locked long enough
+    pub action: SleepAction,
+    /// How to ask systemd to sleep
+    pub backend: Backend,
+    /// How long `touch ~/.no_suspend` blocks sleeping for
+    pub no_suspend: Duration,
+    /// How many multiples of xscreensaver's passwdTimeout to wait before sleeping again
+    pub password_timeout_multiplier: u32,
+    /// Longest to go without checking the timers while locked, in case something else
+    /// put the system to sleep
+    pub poll_interval: Duration,
+    /// systemctl binary, for the systemctl backend
+    pub systemctl: PathBuf,
+    /// xscreensaver-command binary
+    pub xscreensaver_command: PathBuf,
+    /// xrdb binary, for reading xscreensaver settings from the X resource database
+    pub xrdb: PathBuf,
+    /// Directory holding the pre-suspend.d and post-resume.d hooks, or None for the
+    /// default config directory
+    pub hooks: Option<PathBuf>,
+    /// Longest each hook can run before it's killed
+    pub hook_timeout: Duration,
+    /// How long to warn with a notification before sleeping, or zero not to
+    pub grace_period: Duration,
+    /// How long the notification's Cancel button inhibits sleeping for
+    pub cancel_inhibit: Duration,
+    /// What to try if the action doesn't put the system to sleep
+    pub fallback: Option<SleepAction>,
+    /// How long to wait for the system to sleep before deciding it won't
+    pub verify_timeout: Duration,
+    /// How long to wait before trying again after failing to sleep, doubling each time
+    pub retry_backoff: Duration,
+    /// Where sysfs is mounted, for /sys/power
+    pub sysfs: PathBuf,
+    /// When to switch to a deeper sleep if the screen stays locked
+    pub escalate: Option<Escalation>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            action: SleepAction::default(),
+            backend: Backend::default(),
+            no_suspend: Duration::from_secs(8 * 60 * 60),
+            password_timeout_multiplier: 3,
+            poll_interval: Duration::from_secs(30),
+            systemctl: "/usr/bin/systemctl".into(),
+            xscreensaver_command: "/usr/bin/xscreensaver-command".into(),
+            xrdb: "/usr/bin/xrdb".into(),
+            hooks: None,
+            hook_timeout: Duration::from_secs(30),
+            grace_period: Duration::ZERO,
+            cancel_inhibit: Duration::from_secs(30 * 60),
+            fallback: None,
+            verify_timeout: Duration::from_secs(30),
+            retry_backoff: Duration::from_secs(60),
+            sysfs: "/sys".into(),
+            escalate: None,
+        }
+    }
+}
+
+/// Switch to a different action after sleeping repeatedly without being unlocked,
+/// e.g. hibernate a laptop left suspended in a bag over the weekend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Escalation {
+    /// Escalate once we've slept this many times during one lock
+    pub after_sleeps: Option<u32>,
+    /// Escalate once the screen has been locked this long
+    pub after: Option<Duration>,
+    /// What to do instead
+    pub action: SleepAction,
+}
+
+impl Default for Escalation {
+    fn default() -> Self {
+        Escalation {
+            after_sleeps: None,
+            after: None,
+            action: SleepAction::Hibernate,
+        }
+    }
+}
+
+impl Escalation {
+    /// Has the lock gone on long enough to escalate
+    pub fn due(&self, sleeps: u32, locked_for: Duration) -> bool {
+        self.after_sleeps.is_some_and(|after| sleeps >= after)
+            || self.after.is_some_and(|after| locked_for >= after)
+    }
+}
+
+impl Config {
+    /// $XDG_CONFIG_HOME/xscreensaver-suspend, if there's anywhere for it to be
+    pub fn dir() -> Option<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(crate::home_dir()?.join(".config")))?;
+        Some(config_home.join("xscreensaver-suspend"))
+    }
+
+    /// Where the config file lives by default
+    pub fn path() -> Option<PathBuf> {
+        Some(Config::dir()?.join("config.toml"))
+    }
+
+    /// Where the hook directories are
+    pub fn hooks_dir(&self) -> Option<PathBuf> {
+        self.hooks.clone().or_else(Config::dir)
+    }
+
+    /// Load a config file, or the default one if `path` is None.
+    /// It's fine for the default file not to exist.
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let (path, required) = match (path, Config::path()) {
+            (Some(path), _) => (path.to_path_buf(), true),
+            (None, Some(path)) => (path, false),
+            (None, None) => return Ok(Config::default()),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Config::parse(&text).map_err(|e| Error::Parse(path, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(Config::default()),
+            Err(e) => Err(Error::Io(path, e)),
+        }
+    }
+
+    /// What to do, given how many times we've already slept and how long it's been locked
+    pub fn action_for(&self, sleeps: u32, locked_for: Duration) -> &SleepAction {
+        match &self.escalate {
+            Some(escalate) if escalate.due(sleeps, locked_for) => &escalate.action,
+            _ => &self.action,
+        }
+    }
+
+    /// Parse the text of a config file
+    pub fn parse(text: &str) -> Result<Self, toml::Error> {
+        let mut config = Config::default();
+        for (key, (line, value)) in toml::parse(text)? {
+            config
+                .set(&key, &value)
+                .map_err(|message| toml::Error { line, message })?;
+        }
+        Ok(config)
+    }
+
+    /// Change one setting
+    pub fn set(&mut self, key: &str, value: &Value) -> Result<(), String> {
+        let string = || match value {
+            Value::String(s) => Ok(s.as_str()),
+            v => Err(format!("{key} should be a string, not {}", v.type_name())),
+        };
+        let duration = || match value {
+            Value::String(s) => parse_duration(s),
+            _ => Err(format!(
+                "{key} should be a duration such as \"90s\" or \"8h\""
+            )),
+        };
+        let positive = || match value {
+            Value::Integer(n) if *n > 0 => {
+                u32::try_from(*n).map_err(|_| format!("{key} is too big"))
+            }
+            _ => Err(format!("{key} should be a positive integer")),
+        };
+        let action = || match value {
+            Value::Array(argv) => match strings(argv) {
+                Some(argv) if !argv.is_empty() => Ok(SleepAction::Command(argv)),
+                _ => Err(format!(
+                    "{key} command should be a non-empty array of strings"
+                )),
+            },
+            _ => string()?.parse(),
+        };
+        match key {
+            "action" => self.action = action()?,
+            "backend" => self.backend = string()?.parse()?,
+            "no_suspend" => self.no_suspend = duration()?,
+            "password_timeout_multiplier" => self.password_timeout_multiplier = positive()?,
+            "poll_interval" => self.poll_interval = duration()?,
+            "systemctl" => self.systemctl = string()?.into(),
+            "xscreensaver_command" => self.xscreensaver_command = string()?.into(),
+            "xrdb" => self.xrdb = string()?.into(),
+            "hooks" => self.hooks = Some(string()?.into()),
+            "hook_timeout" => self.hook_timeout = duration()?,
+            "grace_period" => self.grace_period = duration()?,
+            "cancel_inhibit" => self.cancel_inhibit = duration()?,
+            "fallback" => self.fallback = Some(action()?),
+            "verify_timeout" => self.verify_timeout = duration()?,
+            "retry_backoff" => self.retry_backoff = duration()?,
+            "sysfs" => self.sysfs = string()?.into(),
+            "escalate.after_sleeps" => self.escalation().after_sleeps = Some(positive()?),
+            "escalate.after" => self.escalation().after = Some(duration()?),
+            "escalate.action" => self.escalation().action = action()?,
+            _ => return Err(format!("unknown setting {key:?}")),
+        }
+        Ok(())
+    }
+
+    /// The escalation settings, creating them if they haven't been set yet
+    fn escalation(&mut self) -> &mut Escalation {
+        self.escalate.get_or_insert_with(Escalation::default)
+    }
+
+    /// Check the settings make sense together, and the programs we need exist
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |message: String| Err(Error::Invalid(message));
+        if self.poll_interval.is_zero() {
+            return invalid("poll_interval must be longer than 0s".into());
+        }
+        if self.hook_timeout.is_zero() {
+            return invalid("hook_timeout must be longer than 0s".into());
+        }
+        if self.verify_timeout.is_zero() {
+            return invalid("verify_timeout must be longer than 0s".into());
+        }
+        if self.retry_backoff.is_zero() {
+            return invalid("retry_backoff must be longer than 0s".into());
+        }
+        if let Some(escalate) = &self.escalate {
+            if escalate.after_sleeps.is_none() && escalate.after.is_none() {
+                return invalid("[escalate] needs after_sleeps or after".into());
+            }
+        }
+        let mut programs = vec![("xscreensaver_command", &self.xscreensaver_command)];
+        if self.backend == Backend::Systemctl {
+            programs.push(("systemctl", &self.systemctl));
+        }
+        for (key, program) in programs {
+            if !program.is_absolute() {
+                return invalid(format!(
+                    "{key} should be an absolute path, not {}",
+                    program.display()
+                ));
+            }
+            is_file(key, program)?;
+        }
+        // Commands without a path are looked up in $PATH when they're run
+        let actions = [
+            ("action", Some(&self.action)),
+            ("fallback", self.fallback.as_ref()),
+            ("escalate.action", self.escalate.as_ref().map(|e| &e.action)),
+        ];
+        for (key, action) in actions {
+            if let Some(SleepAction::Command(argv)) = action {
+                if argv[0].contains('/') {
+                    is_file(key, Path::new(&argv[0]))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check a program we'll run exists
+fn is_file(key: &str, path: &Path) -> Result<(), Error> {
+    match path.metadata() {
+        Ok(metadata) if metadata.is_file() => Ok(()),
+        Ok(_) => Err(Error::Invalid(format!(
+            "{key}: {} isn't a file",
+            path.display()
+        ))),
+        Err(e) => Err(Error::Invalid(format!("{key}: {}: {e}", path.display()))),
+    }
+}
+
+/// Parse a duration such as `90s`, `15m`, `1h30m` or `2d`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    // Far enough to mean forever, near enough to add to any time
+    const MAX_DAYS: u64 = 100 * 365;
+    let bad = || format!("invalid duration {s:?}, expected something like 90s, 15m, 1h30m or 2d");
+    let mut total = 0u64;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(bad());
+    }
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(bad)?;
+        let n: u64 = rest[..digits].parse().map_err(|_| bad())?;
+        let unit_len = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len() - digits);
+        let unit = match &rest[digits..digits + unit_len] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(bad()),
+        };
+        total = n
+            .checked_mul(unit)
+            .and_then(|secs| total.checked_add(secs))
+            .ok_or_else(bad)?;
+        rest = &rest[digits + unit_len..];
+    }
+    if total > MAX_DAYS * 24 * 60 * 60 {
+        return Err(format!(
+            "duration {s:?} is out of range, it can be at most {MAX_DAYS}d"
+        ));
+    }
+    Ok(Duration::from_secs(total))
+}
+
+/// The inverse of `parse_duration`, e.g. "1h30m", to the second
+pub fn format_duration(duration: Duration) -> String {
+    let mut secs = duration.as_secs();
+    if secs == 0 {
+        return "0s".into();
+    }
+    let mut formatted = String::new();
+    for (unit, size) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+        if secs >= size {
+            formatted += &format!("{}{unit}", secs / size);
+            secs %= size;
+        }
+    }
+    formatted
+}
+
+/// Every element of an array as a string
+fn strings(values: &[Value]) -> Option<Vec<String>> {
+    values
+        .iter()
+        .map(|v| match v {
+            Value::String(s) => Some(s.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Why the config couldn't be loaded
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::Error),
+    /// A command line flag had a bad value
+    Flag(String, String),
+    /// The settings don't make sense
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            Error::Parse(path, e) => write!(f, "{}: {e}", path.display()),
+            Error::Flag(flag, message) => write!(f, "{flag}: {message}"),
+            Error::Invalid(message) => write!(f, "invalid configuration: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    /// A config whose programs exist, so it validates
+    fn valid() -> Config {
+        Config {
+            xscreensaver_command: "/bin/sh".into(),
+            ..Config::default()
+        }
+    }
+
+    fn invalid(config: Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_every_setting() {
+        let config = Config::parse(
+            r#"
+            action = ["/usr/sbin/pm-suspend", "--quirk-dpms-on"]
+            backend = "systemctl"
+            no_suspend = "1h30m"
+            password_timeout_multiplier = 2
+            poll_interval = "10s"
+            systemctl = "/bin/systemctl"
+            xscreensaver_command = "/opt/xscreensaver-command"
+            xrdb = "/opt/xrdb"
+            hooks = "/etc/xscreensaver-suspend"
+            hook_timeout = "5s"
+            grace_period = "1m"
+            cancel_inhibit = "2h"
+            fallback = "hibernate"
+            verify_timeout = "20s"
+            retry_backoff = "30s"
+            sysfs = "/tmp/sys"
+
+            [escalate]
+            after_sleeps = 3
+            after = "12h"
+            action = "poweroff"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                action: SleepAction::Command(vec![
+                    "/usr/sbin/pm-suspend".into(),
+                    "--quirk-dpms-on".into()
+                ]),
+                backend: Backend::Systemctl,
+                no_suspend: Duration::from_secs(90 * 60),
+                password_timeout_multiplier: 2,
+                poll_interval: Duration::from_secs(10),
+                systemctl: "/bin/systemctl".into(),
+                xscreensaver_command: "/opt/xscreensaver-command".into(),
+                xrdb: "/opt/xrdb".into(),
+                hooks: Some("/etc/xscreensaver-suspend".into()),
+                hook_timeout: Duration::from_secs(5),
+                grace_period: Duration::from_secs(60),
+                cancel_inhibit: Duration::from_secs(2 * 60 * 60),
+                fallback: Some(SleepAction::Hibernate),
+                verify_timeout: Duration::from_secs(20),
+                retry_backoff: Duration::from_secs(30),
+                sysfs: "/tmp/sys".into(),
+                escalate: Some(Escalation {
+                    after_sleeps: Some(3),
+                    after: Some(Duration::from_secs(12 * 60 * 60)),
+                    action: SleepAction::PowerOff,
+                }),
+            }
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn reports_bad_settings_with_their_line() {
+        let error = |text| Config::parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("\naction = \"nap\"\n"),
+            "line 2: unknown sleep action \"nap\", expected suspend, hibernate, hybrid-sleep, \
+             suspend-then-hibernate, poweroff, mem, disk, freeze, standby, or a command as an array"
+        );
+        assert_eq!(error("colour = 1"), "line 1: unknown setting \"colour\"");
+        assert_eq!(
+            error("poll_interval = 30"),
+            "line 1: poll_interval should be a duration such as \"90s\" or \"8h\""
+        );
+        assert_eq!(
+            error("password_timeout_multiplier = 0"),
+            "line 1: password_timeout_multiplier should be a positive integer"
+        );
+        assert_eq!(
+            error("xrdb = 1"),
+            "line 1: xrdb should be a string, not integer"
+        );
+        assert_eq!(
+            error("action = []"),
+            "line 1: action command should be a non-empty array of strings"
+        );
+        assert_eq!(
+            error("backend = \"dbus\""),
+            "line 1: unknown backend \"dbus\", expected logind or systemctl"
+        );
+    }
+
+    #[test]
+    fn loads_files() {
+        let dir = TempDir::new("config");
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "action = \"hibernate\"\n").unwrap();
+        assert_eq!(
+            Config::load(Some(&path)).unwrap().action,
+            SleepAction::Hibernate
+        );
+
+        std::fs::write(&path, "action = 1\n").unwrap();
+        let error = Config::load(Some(&path)).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "{}: line 1: action should be a string, not integer",
+                path.display()
+            )
+        );
+        drop(dir);
+        // A file that was asked for has to be there
+        assert!(matches!(Config::load(Some(&path)), Err(Error::Io(..))));
+    }
+
+    #[test]
+    fn validates() {
+        valid().validate().unwrap();
+        assert_eq!(
+            invalid(Config {
+                poll_interval: Duration::ZERO,
+                ..valid()
+            }),
+            "invalid configuration: poll_interval must be longer than 0s"
+        );
+        assert_eq!(
+            invalid(Config {
+                verify_timeout: Duration::ZERO,
+                ..valid()
+            }),
+            "invalid configuration: verify_timeout must be longer than 0s"
+        );
+        assert_eq!(
+            invalid(Config {
+                escalate: Some(Escalation::default()),
+                ..valid()
+            }),
+            "invalid configuration: [escalate] needs after_sleeps or after"
+        );
+        assert_eq!(
+            invalid(Config {
+                xscreensaver_command: "xscreensaver-command".into(),
+                ..valid()
+            }),
+            "invalid configuration: xscreensaver_command should be an absolute path, \
+             not xscreensaver-command"
+        );
+        assert_eq!(
+            invalid(Config {
+                backend: Backend::Systemctl,
+                systemctl: "/".into(),
+                ..valid()
+            }),
+            "invalid configuration: systemctl: / isn't a file"
+        );
+        assert!(invalid(Config {
+            fallback: Some(SleepAction::Command(vec!["/nonexistent/pm-suspend".into()])),
+            ..valid()
+        })
+        .starts_with("invalid configuration: fallback: /nonexistent/pm-suspend: "));
+        assert!(invalid(Config {
+            escalate: Some(Escalation {
+                after_sleeps: Some(1),
+                action: SleepAction::Command(vec!["/nonexistent/pm-hibernate".into()]),
+                ..Escalation::default()
+            }),
+            ..valid()
+        })
+        .starts_with("invalid configuration: escalate.action: /nonexistent/pm-hibernate: "));
+        // Found in $PATH when it's run
+        Config {
+            action: SleepAction::Command(vec!["pm-suspend".into()]),
+            ..valid()
+        }
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn escalates() {
+        let config = Config {
+            escalate: Some(Escalation {
+                after_sleeps: Some(3),
+                after: Some(Duration::from_secs(60 * 60)),
+                action: SleepAction::Hibernate,
+            }),
+            ..Config::default()
+        };
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        assert_eq!(config.action_for(2, minutes(59)), &SleepAction::Suspend);
+        assert_eq!(config.action_for(3, minutes(1)), &SleepAction::Hibernate);
+        assert_eq!(config.action_for(0, minutes(60)), &SleepAction::Hibernate);
+    }
+
+    #[test]
+    fn durations_round_trip() {
+        for (text, secs) in [
+            ("0s", 0),
+            ("45s", 45),
+            ("1m30s", 90),
+            ("15m", 15 * 60),
+            ("1h30m", 90 * 60),
+            ("2d", 2 * 24 * 60 * 60),
+            ("1d2h3m4s", 93784),
+        ] {
+            assert_eq!(parse_duration(text), Ok(Duration::from_secs(secs)));
+            assert_eq!(format_duration(Duration::from_secs(secs)), text);
+        }
+        // Normalised on the way back
+        assert_eq!(
+            parse_duration("90m").map(format_duration),
+            Ok("1h30m".into())
+        );
+        assert_eq!(format_duration(Duration::from_millis(1999)), "1s");
+        for bad in [
+            "",
+            "15",
+            "m",
+            "1x",
+            "1.5h",
+            "-1s",
+            "99999999999999999999s",
+            "9999999999999999d",
+        ] {
+            assert!(parse_duration(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_durations_out_of_range() {
+        assert_eq!(
+            parse_duration("36500d"),
+            Ok(Duration::from_secs(36500 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("36500d1s"),
+            Err("duration \"36500d1s\" is out of range, it can be at most 36500d".into())
+        );
+        let error = Config::parse("no_suspend = \"200000000000000d\"").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 1: duration \"200000000000000d\" is out of range, it can be at most 36500d"
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! The daemon's control socket, and a client for it.
+//!
+//! A connection carries one request and its reply. The request is a command on the first
+//! line, such as `status` or `inhibit`, followed by any arguments as TOML. The reply is `ok`
+//! followed by anything it returns, or `error` and why.
+
+use crate::{
+    inhibit::Inhibit,
+    status::DaemonStatus,
+    toml::{self, Value},
+};
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread,
+    time::Duration,
+};
+
+/// How long the server waits for a client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a client waits for the reply, which can be held up by sleeping
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+/// Requests are a few lines; anything longer isn't one
+const MAX_REQUEST: u64 = 64 * 1024;
+
+/// Something to ask the daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// What it's doing, replied to with a `DaemonStatus`
+    Status,
+    /// Block sleep, replied to with the new inhibit's ID
+    Inhibit(Inhibit),
+    /// Remove these inhibits, or all of them if there are none
+    Uninhibit(Vec<String>),
+    /// Sleep now, if the screen is locked
+    SuspendNow,
+    /// Load xscreensaver's settings again
+    Reload,
+    /// Don't sleep until resumed
+    Pause,
+    Resume,
+}
+
+impl Request {
+    fn parse(text: &str) -> Result<Self, String> {
+        let (command, body) = text.split_once('\n').unwrap_or((text, ""));
+        let request = match command {
+            "status" => Request::Status,
+            "inhibit" => Request::Inhibit(Inhibit::parse("", body).map_err(|e| e.to_string())?),
+            "uninhibit" => {
+                let mut ids = Vec::new();
+                for (key, (line, value)) in toml::parse(body).map_err(|e| e.to_string())? {
+                    let values = match (key.as_str(), value) {
+                        ("ids", Value::Array(values)) => values,
+                        ("ids", _) => return Err(format!("line {line}: ids should be an array")),
+                        _ => return Err(format!("line {line}: {key} isn't a known key")),
+                    };
+                    for value in values {
+                        let Value::String(id) = value else {
+                            return Err(format!("line {line}: IDs should be strings"));
+                        };
+                        ids.push(id);
+                    }
+                }
+                Request::Uninhibit(ids)
+            }
+            "suspend-now" => Request::SuspendNow,
+            "reload" => Request::Reload,
+            "pause" => Request::Pause,
+            "resume" => Request::Resume,
+            _ => return Err(format!("unknown request {command:?}")),
+        };
+        Ok(request)
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            Request::Status => "status\n".into(),
+            Request::Inhibit(inhibit) => format!("inhibit\n{}", inhibit.to_toml()),
+            Request::Uninhibit(ids) => {
+                let ids = ids.iter().map(|id| Value::String(id.clone())).collect();
+                format!("uninhibit\nids = {}\n", Value::Array(ids))
+            }
+            Request::SuspendNow => "suspend-now\n".into(),
+            Request::Reload => "reload\n".into(),
+            Request::Pause => "pause\n".into(),
+            Request::Resume => "resume\n".into(),
+        }
+    }
+}
+
+/// What the daemon answers: anything it returns, or why it refused
+pub type Reply = Result<String, String>;
+
+/// A request for the main loop, and where to send its reply
+#[derive(Debug)]
+pub struct Control {
+    pub request: Request,
+    pub reply: Sender<Reply>,
+}
+
+/// $XDG_RUNTIME_DIR/xscreensaver-suspend/control, if there's a runtime directory
+pub fn socket_path() -> Option<PathBuf> {
+    Some(crate::runtime_dir()?.join("xscreensaver-suspend/control"))
+}
+
+/// Listen on `path`, sending requests to the main loop and writing back its replies.
+/// Fails if another daemon is already listening there.
+pub fn spawn_server<T>(path: &Path, tx: Sender<T>) -> Result<(), Error>
+where
+    T: From<Control> + Send + 'static,
+{
+    let io_error = |e| Error::Io(path.into(), e);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::AlreadyRunning(path.into()));
+            }
+            // Left behind by a daemon that's gone
+            fs::remove_file(path).map_err(io_error)?;
+            UnixListener::bind(path).map_err(io_error)?
+        }
+        Err(e) => return Err(io_error(e)),
+    };
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if !serve(stream, &tx) {
+                        return;
+                    }
+                }
+                Err(e) => eprintln!("Control socket: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Answer one connection. Returns false once the main loop has gone away.
+fn serve<T: From<Control>>(mut stream: UnixStream, tx: &Sender<T>) -> bool {
+    let mut text = String::new();
+    let read = stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .and_then(|()| (&mut stream).take(MAX_REQUEST).read_to_string(&mut text));
+    if let Err(e) = read {
+        eprintln!("Reading a control request: {e}");
+        return true;
+    }
+    let reply = match Request::parse(&text) {
+        Ok(request) => {
+            let (reply, rx) = mpsc::channel();
+            if tx.send(Control { request, reply }.into()).is_err() {
+                return false;
+            }
+            rx.recv()
+                .unwrap_or_else(|_| Err("the daemon stopped".into()))
+        }
+        Err(e) => Err(e),
+    };
+    let text = match reply {
+        Ok(body) => format!("ok\n{body}"),
+        Err(message) => format!("error {message}\n"),
+    };
+    // The client may have given up waiting
+    let _ = stream.write_all(text.as_bytes());
+    true
+}
+
+/// Talks to the daemon listening on a control socket
+#[derive(Debug, Clone)]
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Client { path: path.into() }
+    }
+
+    /// The daemon at `socket_path`
+    pub fn for_user() -> Result<Self, Error> {
+        Ok(Client::new(socket_path().ok_or(Error::NoRuntimeDir)?))
+    }
+
+    /// Send a request and wait for the reply
+    pub fn send(&self, request: &Request) -> Result<String, Error> {
+        let io_error = |e| Error::Io(self.path.clone(), e);
+        let mut stream = UnixStream::connect(&self.path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => Error::NotRunning,
+            _ => io_error(e),
+        })?;
+        stream
+            .set_read_timeout(Some(REPLY_TIMEOUT))
+            .map_err(io_error)?;
+        stream
+            .write_all(request.to_text().as_bytes())
+            .map_err(io_error)?;
+        stream.shutdown(Shutdown::Write).map_err(io_error)?;
+        let mut text = String::new();
+        stream.read_to_string(&mut text).map_err(io_error)?;
+        match text.split_once('\n') {
+            Some(("ok", body)) => Ok(body.into()),
+            Some((error, _)) if error.starts_with("error ") => {
+                Err(Error::Refused(error["error ".len()..].into()))
+            }
+            _ => Err(Error::BadReply(format!("{text:?}"))),
+        }
+    }
+
+    pub fn status(&self) -> Result<DaemonStatus, Error> {
+        let body = self.send(&Request::Status)?;
+        DaemonStatus::parse(&body).map_err(|e| Error::BadReply(e.to_string()))
+    }
+
+    /// Add an inhibit, returning its ID
+    pub fn inhibit(&self, inhibit: &Inhibit) -> Result<String, Error> {
+        let body = self.send(&Request::Inhibit(inhibit.clone()))?;
+        inhibit_id(&body).ok_or_else(|| Error::BadReply(format!("no ID in {body:?}")))
+    }
+}
+
+/// The new inhibit's ID, from the reply to `Request::Inhibit`
+pub(crate) fn inhibit_id(body: &str) -> Option<String> {
+    match toml::parse(body).ok()?.remove("id") {
+        Some((_, Value::String(id))) => Some(id),
+        _ => None,
+    }
+}
+
+/// Why the daemon couldn't be talked to
+#[derive(Debug)]
+pub enum Error {
+    /// XDG_RUNTIME_DIR isn't set, so there's nowhere for the socket
+    NoRuntimeDir,
+    /// Nothing is listening on the socket
+    NotRunning,
+    /// Another daemon is listening on the socket
+    AlreadyRunning(PathBuf),
+    Io(PathBuf, io::Error),
+    /// The daemon wouldn't do it, and said why
+    Refused(String),
+    /// The reply didn't make sense
+    BadReply(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoRuntimeDir => write!(f, "XDG_RUNTIME_DIR isn't set"),
+            Error::NotRunning => write!(f, "xscreensaver-suspend isn't running"),
+            Error::AlreadyRunning(path) => {
+                write!(f, "already running, listening on {}", path.display())
+            }
+            Error::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            Error::Refused(message) => write!(f, "{message}"),
+            Error::BadReply(message) => write!(f, "unexpected reply from the daemon: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::Status,
+            Request::Inhibit(Inhibit {
+                id: String::new(),
+                until: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                process: None,
+                reason: "say \"hi\"".into(),
+            }),
+            Request::Uninhibit(vec!["1".into(), "backup".into()]),
+            Request::Uninhibit(Vec::new()),
+            Request::SuspendNow,
+            Request::Reload,
+            Request::Pause,
+            Request::Resume,
+        ];
+        for request in requests {
+            assert_eq!(Request::parse(&request.to_text()), Ok(request));
+        }
+        assert!(Request::parse("sleep\n").is_err());
+        assert!(Request::parse("uninhibit\nids = 1\n").is_err());
+    }
+
+    #[test]
+    fn client_and_server_talk() {
+        let dir = TempDir::new("control");
+        let path = dir.path().join("control");
+        let (tx, rx) = mpsc::channel::<Control>();
+        spawn_server(&path, tx.clone()).unwrap();
+        assert!(matches!(
+            spawn_server(&path, tx),
+            Err(Error::AlreadyRunning(_))
+        ));
+        thread::spawn(move || {
+            for control in rx {
+                let reply = match control.request {
+                    Request::Inhibit(inhibit) => Ok(format!("id = \"{}\"\n", inhibit.reason)),
+                    Request::SuspendNow => Err("the screen isn't locked".into()),
+                    _ => Ok(String::new()),
+                };
+                control.reply.send(reply).unwrap();
+            }
+        });
+
+        let client = Client::new(&path);
+        let inhibit = Inhibit {
+            id: String::new(),
+            until: Some(SystemTime::now()),
+            process: None,
+            reason: "7".into(),
+        };
+        assert_eq!(client.inhibit(&inhibit).unwrap(), "7");
+        assert_eq!(client.send(&Request::Pause).unwrap(), "");
+        assert!(matches!(
+            client.send(&Request::SuspendNow),
+            Err(Error::Refused(message)) if message == "the screen isn't locked"
+        ));
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            client.send(&Request::Status),
+            Err(Error::NotRunning)
+        ));
+    }
+}
@@ -0,0 +1,502 @@
+//! The daemon: follows xscreensaver and sleeps when the state machine says to
+
+use crate::{
+    clock::{BootInstant, Clock, ClockWatch, SystemClock},
+    config::{format_duration, Config},
+    control::{self, spawn_server, Control, Reply, Request},
+    dbus::Connection,
+    error::Error,
+    hooks::{Context, Hooks},
+    inhibit::{self, logind_inhibitors, InhibitDir},
+    machine::{Action, LockState, Outcome, SuspendStateMachine, Trigger, MIN_SLEEP},
+    notify::Notifier,
+    reload::spawn_reloader,
+    service::{spawn_service, Signals},
+    settings::XscreensaverSettings,
+    sleep::SleepAction,
+    status::DaemonStatus,
+    toml::Value,
+    verify::Baseline,
+    watch::{spawn_xscreensaver_watch, WatchEvent},
+};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, SystemTime},
+};
+
+/// Longest to wait before trying again after failing to sleep
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// Everything the main loop waits for
+enum Message {
+    Watch(WatchEvent),
+    /// ~/.xscreensaver changed, or we were sent SIGHUP
+    Settings(Box<XscreensaverSettings>),
+    /// A request on the control socket or the session bus
+    Control(Control),
+}
+
+impl From<WatchEvent> for Message {
+    fn from(event: WatchEvent) -> Self {
+        Message::Watch(event)
+    }
+}
+
+impl From<XscreensaverSettings> for Message {
+    fn from(settings: XscreensaverSettings) -> Self {
+        Message::Settings(Box::new(settings))
+    }
+}
+
+impl From<Control> for Message {
+    fn from(control: Control) -> Self {
+        Message::Control(control)
+    }
+}
+
+/// Watch xscreensaver and sleep while it's locked. Only returns if that becomes impossible.
+pub fn run(config: Config, settings: XscreensaverSettings) -> Result<Infallible, Error> {
+    let (tx, rx) = mpsc::channel();
+    // First, so no other thread gets SIGHUP
+    spawn_reloader(config.xrdb.clone(), tx.clone());
+    match control::socket_path() {
+        Some(path) => spawn_server(&path, tx.clone())?,
+        None => eprintln!("XDG_RUNTIME_DIR isn't set, so there's no control socket"),
+    }
+    let no_suspend = config.no_suspend;
+    let signals = Connection::session()
+        .and_then(|conn| spawn_service(conn, tx.clone(), move || inhibit::active(no_suspend)));
+    let signals = match signals {
+        Ok(signals) => Some(signals),
+        Err(e) => {
+            eprintln!("Not on the session bus: {e}");
+            None
+        }
+    };
+    let notifier = match config.grace_period.is_zero() {
+        true => None,
+        false => {
+            let cancel_inhibit = config.cancel_inhibit;
+            let notifier = Connection::session()
+                .and_then(|conn| Notifier::spawn(conn, move || cancelled(cancel_inhibit)));
+            match notifier {
+                Ok(notifier) => Some(notifier),
+                Err(e) => {
+                    eprintln!("Can't warn with notifications before sleeping: {e}");
+                    None
+                }
+            }
+        }
+    };
+    spawn_xscreensaver_watch(config.xscreensaver_command.clone(), tx);
+
+    let mut daemon = Daemon::new(config, settings, signals, notifier);
+    loop {
+        // Nothing can happen while unlocked until xscreensaver says something, so don't wake up.
+        // While locked, the wait is capped because it stops while something else puts the
+        // system to sleep, and logind inhibitors can go away at any time.
+        let message = match daemon.deadline {
+            Some(deadline) => {
+                let wait = deadline
+                    .duration_since(daemon.clock.now())
+                    .min(daemon.config.poll_interval);
+                match rx.recv_timeout(wait) {
+                    Ok(message) => Some(message),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return Err(Error::WatcherLost),
+                }
+            }
+            None => Some(rx.recv().map_err(|_| Error::WatcherLost)?),
+        };
+        let elapsed = daemon.clock.tick();
+        if elapsed.asleep >= MIN_SLEEP {
+            daemon.woken(elapsed.asleep);
+        }
+        let actions = daemon.machine.tick(daemon.clock.now(), elapsed);
+        daemon.perform(actions);
+        let actions = match message {
+            Some(Message::Settings(new)) => daemon.set_settings(*new),
+            Some(Message::Watch(event)) => daemon.machine.event(&event, daemon.clock.now()),
+            Some(Message::Control(Control { request, reply })) => {
+                let (answer, actions) = daemon.control(request);
+                // The client may have given up waiting
+                let _ = reply.send(answer);
+                actions
+            }
+            None => Vec::new(),
+        };
+        daemon.perform(actions);
+    }
+}
+
+/// What the main loop keeps track of
+struct Daemon {
+    config: Config,
+    settings: XscreensaverSettings,
+    machine: SuspendStateMachine,
+    clock: ClockWatch<SystemClock>,
+    /// When the state machine next needs to be ticked
+    deadline: Option<BootInstant>,
+    /// Not sleeping until resumed
+    paused: bool,
+    /// For the D-Bus service's signals, if it's on the session bus
+    signals: Option<Signals>,
+    hooks: Option<Hooks>,
+    /// For warning before sleeping, if there's a grace period and a session bus
+    notifier: Option<Notifier>,
+    /// The last sleep we started, for the post-resume hooks once we wake up
+    slept: Option<Context>,
+    /// How many times in a row it's failed to sleep, for backing off
+    failures: u32,
+}
+
+impl Daemon {
+    fn new(
+        config: Config,
+        settings: XscreensaverSettings,
+        signals: Option<Signals>,
+        notifier: Option<Notifier>,
+    ) -> Self {
+        let mut machine = SuspendStateMachine::new(
+            Self::dpms_off(&settings),
+            Self::password_timeout(&settings, &config),
+        );
+        machine.set_grace_period(config.grace_period);
+        let hooks = config
+            .hooks_dir()
+            .map(|dir| Hooks::new(dir, config.hook_timeout));
+        Daemon {
+            config,
+            settings,
+            machine,
+            clock: ClockWatch::new(SystemClock),
+            deadline: None,
+            paused: false,
+            signals,
+            hooks,
+            notifier,
+            slept: None,
+            failures: 0,
+        }
+    }
+
+    /// When to sleep after locking, or None if the screen never turns off
+    fn dpms_off(settings: &XscreensaverSettings) -> Option<Duration> {
+        settings.dpms_enabled.then_some(settings.dpms_off)
+    }
+
+    /// How long to wait after an unlock attempt before sleeping again
+    fn password_timeout(settings: &XscreensaverSettings, config: &Config) -> Duration {
+        settings
+            .password_timeout
+            .saturating_mul(config.password_timeout_multiplier)
+    }
+
+    fn set_settings(&mut self, settings: XscreensaverSettings) -> Vec<Action> {
+        if !settings.dpms_enabled {
+            eprintln!("DPMS isn't enabled in xscreensaver's settings, not sleeping until it is");
+        }
+        self.settings = settings;
+        self.machine.set_timeouts(
+            Self::dpms_off(&self.settings),
+            Self::password_timeout(&self.settings, &self.config),
+            self.clock.now(),
+        )
+    }
+
+    /// Answer a request from the control socket or the session bus
+    fn control(&mut self, request: Request) -> (Reply, Vec<Action>) {
+        let done = |result: Result<(), String>| result.map(|()| String::new());
+        match request {
+            Request::Status => (Ok(self.status().to_toml()), Vec::new()),
+            Request::Inhibit(inhibit) => {
+                let added = InhibitDir::for_user()
+                    .and_then(|dir| dir.add(inhibit.until, inhibit.process, &inhibit.reason))
+                    .map(|inhibit| format!("id = {}\n", Value::String(inhibit.id)))
+                    .map_err(|e| e.to_string());
+                (added, Vec::new())
+            }
+            Request::Uninhibit(ids) => {
+                let removed = InhibitDir::for_user().and_then(|dir| dir.uninhibit(&ids));
+                (done(removed.map_err(|e| e.to_string())), Vec::new())
+            }
+            Request::SuspendNow if self.machine.snapshot().state == LockState::Unlocked => {
+                (Err("the screen isn't locked".into()), Vec::new())
+            }
+            Request::SuspendNow => (
+                Ok(String::new()),
+                self.machine.suspend_now(self.clock.now()),
+            ),
+            Request::Reload => {
+                println!("Reloading xscreensaver settings: asked to");
+                match XscreensaverSettings::load(&self.config.xrdb) {
+                    Ok(settings) => (Ok(String::new()), self.set_settings(settings)),
+                    Err(e) => (Err(e.to_string()), Vec::new()),
+                }
+            }
+            Request::Pause => {
+                println!("Paused, not sleeping until resumed");
+                self.paused = true;
+                (Ok(String::new()), Vec::new())
+            }
+            Request::Resume => {
+                println!("Resumed");
+                self.paused = false;
+                (Ok(String::new()), Vec::new())
+            }
+        }
+    }
+
+    /// The grace period has started, and it's going to sleep at `at` unless unlocked
+    fn warn(&self, at: BootInstant) {
+        let action = self.status().action;
+        let left = at.duration_since(self.clock.now());
+        // Rounded, as a little time has passed since the grace period started
+        let secs = left.saturating_add(Duration::from_millis(500)).as_secs();
+        println!("Going to {action} in {secs}s unless unlocked");
+        let Some(notifier) = &self.notifier else {
+            return;
+        };
+        // It's not going to sleep after all
+        if !inhibit::active(self.config.no_suspend).is_empty() {
+            return;
+        }
+        notifier.show(
+            &format!("Going to {action} in {secs}s"),
+            &format!(
+                "Unlock the screen, or cancel to stay awake for {}",
+                format_duration(self.config.cancel_inhibit)
+            ),
+            left,
+        );
+    }
+
+    /// Woken up after being asleep this long
+    fn woken(&mut self, asleep: Duration) {
+        if let Some(signals) = &self.signals {
+            signals.resumed(asleep);
+        }
+        if let (Some(hooks), Some(context)) = (&self.hooks, self.slept.take()) {
+            hooks.post_resume(&context, asleep);
+        }
+    }
+
+    /// What the state machine is doing, for `xscreensaver-suspend status`
+    fn status(&self) -> DaemonStatus {
+        let snapshot = self.machine.snapshot();
+        let now = self.clock.now();
+        let locked_for = match (snapshot.locked_since, snapshot.next_suspend) {
+            (Some(since), Some(at)) => at.duration_since(since),
+            _ => Duration::ZERO,
+        };
+        DaemonStatus {
+            pid: std::process::id(),
+            paused: self.paused,
+            state: snapshot.state,
+            locked_since: snapshot
+                .locked_since
+                .and_then(|since| wall_time(since, now)),
+            sleeps: snapshot.sleeps,
+            asleep: snapshot.asleep,
+            next_suspend: snapshot.next_suspend.and_then(|at| wall_time(at, now)),
+            action: self
+                .config
+                .action_for(snapshot.sleeps, locked_for)
+                .to_string(),
+            settings: self.settings.clone(),
+        }
+    }
+
+    /// Do what the state machine says, including anything it says after sleeping
+    fn perform(&mut self, actions: Vec<Action>) {
+        let mut actions = VecDeque::from(actions);
+        while let Some(action) = actions.pop_front() {
+            match action {
+                Action::Log(message) => println!("{message}"),
+                Action::Warn(_) if self.paused => {}
+                Action::Warn(at) => self.warn(at),
+                Action::Unwarn => {
+                    if let Some(notifier) = &self.notifier {
+                        notifier.close();
+                    }
+                }
+                Action::Suspend { .. } if self.paused => {
+                    println!("Paused, not going to sleep");
+                    let outcome = Outcome::NotSlept { retry_at: None };
+                    actions.extend(self.machine.suspended(outcome, self.clock.now()));
+                }
+                Action::Suspend {
+                    trigger,
+                    sleeps,
+                    locked_for,
+                } => {
+                    if let Some(notifier) = &self.notifier {
+                        notifier.close();
+                    }
+                    let mut context = Context {
+                        trigger,
+                        action: self.config.action_for(sleeps, locked_for).clone(),
+                        locked_for,
+                        sleeps,
+                    };
+                    if trigger == Trigger::DpmsElapsed {
+                        self.failures = 0;
+                    }
+                    let outcome = suspend(
+                        &self.config,
+                        self.signals.as_ref(),
+                        self.hooks.as_ref(),
+                        &mut context,
+                        self.clock.clock(),
+                        self.failures,
+                    );
+                    match outcome {
+                        Outcome::Slept => self.failures = 0,
+                        Outcome::Failed { .. } => self.failures += 1,
+                        Outcome::NotSlept { .. } => {}
+                    }
+                    self.slept = (outcome == Outcome::Slept).then_some(context);
+                    actions.extend(self.machine.suspended(outcome, self.clock.now()));
+                }
+                Action::ScheduleWake(at) => self.deadline = at,
+            }
+        }
+    }
+}
+
+/// The wall clock time at `at`, going by how far it is from `now`, or None if it's too far
+/// away to be a wall clock time
+fn wall_time(at: BootInstant, now: BootInstant) -> Option<SystemTime> {
+    match at >= now {
+        true => SystemTime::now().checked_add(at.duration_since(now)),
+        false => SystemTime::now().checked_sub(now.duration_since(at)),
+    }
+}
+
+/// Put the system to sleep, unless something stops it. The action is escalated if it's been
+/// locked for long enough, and the fallback is tried if it doesn't work. If neither does,
+/// it's tried again after backing off for the `failures` in a row so far, and `context` says
+/// which one worked.
+fn suspend(
+    config: &Config,
+    signals: Option<&Signals>,
+    hooks: Option<&Hooks>,
+    context: &mut Context,
+    clock: &impl Clock,
+    failures: u32,
+) -> Outcome {
+    let now = clock.now();
+    let action = &context.action;
+    let inhibits = inhibit::active(config.no_suspend);
+    if !inhibits.is_empty() {
+        for inhibit in &inhibits {
+            println!("Not going to {action}, inhibited by {inhibit}");
+        }
+        // Try again once they've all expired, unless one lasts until it's removed
+        let until = inhibits
+            .iter()
+            .map(|inhibit| inhibit.until)
+            .collect::<Option<Vec<_>>>()
+            .and_then(|until| until.into_iter().max());
+        return Outcome::NotSlept {
+            retry_at: until
+                .map(|until| now + until.duration_since(SystemTime::now()).unwrap_or_default()),
+        };
+    }
+    if logind_inhibited(action) {
+        return Outcome::NotSlept { retry_at: None };
+    }
+    if action != &config.action {
+        println!(
+            "Escalating to {action} after sleeping {} times, locked for {}s",
+            context.sleeps,
+            context.locked_for.as_secs()
+        );
+    }
+    if let Some(Err(e)) = hooks.map(|hooks| hooks.pre_suspend(context)) {
+        println!("Not going to {action}, vetoed by {e}");
+        return Outcome::NotSlept { retry_at: None };
+    }
+    let mut actions = vec![action.clone()];
+    actions.extend(
+        config
+            .fallback
+            .clone()
+            .filter(|fallback| fallback != action),
+    );
+    for (i, action) in actions.into_iter().enumerate() {
+        if i > 0 {
+            println!("Falling back to {action}");
+        }
+        if let Some(signals) = signals {
+            signals.will_suspend(&action.to_string());
+        }
+        if sleep(config, &action, clock) {
+            context.action = action;
+            return Outcome::Slept;
+        }
+    }
+    let backoff = config
+        .retry_backoff
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(MAX_BACKOFF);
+    println!("Trying again in {}", format_duration(backoff));
+    Outcome::Failed {
+        retry_at: clock.now() + backoff,
+    }
+}
+
+/// Ask for `action`, and wait to see that the system really slept
+fn sleep(config: &Config, action: &SleepAction, clock: &impl Clock) -> bool {
+    let baseline = Baseline::take(clock, &config.sysfs);
+    if let Err(e) = action.perform(config.backend, &config.systemctl, &config.sysfs) {
+        eprintln!("{action} failed: {e}");
+        return false;
+    }
+    // There's no waking up to check
+    if *action == SleepAction::PowerOff {
+        return true;
+    }
+    match baseline.wait(clock, config.verify_timeout) {
+        Ok(_) => true,
+        Err(e) => {
+            eprintln!("{action} didn't work: {e}");
+            false
+        }
+    }
+}
+
+/// The notification's Cancel button was pressed, so don't sleep for a while
+fn cancelled(inhibit_for: Duration) {
+    let until = SystemTime::now() + inhibit_for;
+    let reason = "Cancelled from the notification";
+    match InhibitDir::for_user().and_then(|dir| dir.add(Some(until), None, reason)) {
+        Ok(inhibit) => println!(
+            "{reason}, inhibiting for {}: {}",
+            format_duration(inhibit_for),
+            inhibit.id
+        ),
+        Err(e) => eprintln!("Couldn't inhibit after cancelling: {e}"),
+    }
+}
+
+/// Don't sleep while a program holds a logind inhibitor lock, e.g. with `systemd-inhibit`.
+/// The sleep is retried after the password timeout.
+fn logind_inhibited(action: &SleepAction) -> bool {
+    let inhibitors = match logind_inhibitors(action) {
+        Ok(inhibitors) => inhibitors,
+        Err(e) => {
+            eprintln!("Couldn't check logind inhibitors: {e}");
+            return false;
+        }
+    };
+    for inhibitor in &inhibitors {
+        println!(
+            "Not going to {action}, inhibited by {} (pid {}): {}",
+            inhibitor.who, inhibitor.pid, inhibitor.why
+        );
+    }
+    !inhibitors.is_empty()
+}
@@ -0,0 +1,909 @@
+//! Minimal D-Bus client speaking the wire protocol directly over a Unix socket
+
+use std::{
+    collections::VecDeque,
+    ffi::OsStr,
+    fmt,
+    io::{self, Read, Write},
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixStream},
+        },
+    },
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// Used when DBUS_SYSTEM_BUS_ADDRESS isn't set
+const SYSTEM_BUS: &str = "unix:path=/var/run/dbus/system_bus_socket";
+/// How long to wait for a method reply, matching libdbus
+const CALL_TIMEOUT: Duration = Duration::from_secs(25);
+/// Messages can't be bigger than this
+const MAX_MESSAGE: usize = 128 * 1024 * 1024;
+
+/// A value in a message body
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    Bool(bool),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(String),
+    Signature(String),
+    UnixFd(u32),
+    /// Signature of the elements, and the elements
+    Array(String, Vec<Value>),
+    Struct(Vec<Value>),
+    DictEntry(Box<Value>, Box<Value>),
+    Variant(Box<Value>),
+}
+
+impl Value {
+    /// The D-Bus type signature of this value
+    pub fn signature(&self) -> String {
+        match self {
+            Value::Byte(_) => "y".into(),
+            Value::Bool(_) => "b".into(),
+            Value::Int16(_) => "n".into(),
+            Value::UInt16(_) => "q".into(),
+            Value::Int32(_) => "i".into(),
+            Value::UInt32(_) => "u".into(),
+            Value::Int64(_) => "x".into(),
+            Value::UInt64(_) => "t".into(),
+            Value::Double(_) => "d".into(),
+            Value::String(_) => "s".into(),
+            Value::ObjectPath(_) => "o".into(),
+            Value::Signature(_) => "g".into(),
+            Value::UnixFd(_) => "h".into(),
+            Value::Array(element, _) => format!("a{element}"),
+            Value::Struct(fields) => {
+                format!(
+                    "({})",
+                    fields.iter().map(Value::signature).collect::<String>()
+                )
+            }
+            Value::DictEntry(key, value) => format!("{{{}{}}}", key.signature(), value.signature()),
+            Value::Variant(_) => "v".into(),
+        }
+    }
+
+    /// A string, object path or signature
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) | Value::ObjectPath(s) | Value::Signature(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Any unsigned integer that fits in a u32
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Value::Byte(n) => Some(u32::from(*n)),
+            Value::UInt16(n) => Some(u32::from(*n)),
+            Value::UInt32(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Elements of an array, or fields of a struct
+    pub fn as_slice(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(_, items) | Value::Struct(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MessageKind {
+    #[default]
+    MethodCall = 1,
+    MethodReturn = 2,
+    Error = 3,
+    Signal = 4,
+}
+
+/// A D-Bus message
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    pub kind: MessageKind,
+    pub flags: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub body: Vec<Value>,
+}
+
+impl Message {
+    pub fn method_call(
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        body: Vec<Value>,
+    ) -> Self {
+        Message {
+            kind: MessageKind::MethodCall,
+            destination: Some(destination.into()),
+            path: Some(path.into()),
+            interface: Some(interface.into()),
+            member: Some(member.into()),
+            body,
+            ..Default::default()
+        }
+    }
+
+    /// A signal from one of this connection's objects
+    pub fn signal(path: &str, interface: &str, member: &str, body: Vec<Value>) -> Self {
+        Message {
+            kind: MessageKind::Signal,
+            path: Some(path.into()),
+            interface: Some(interface.into()),
+            member: Some(member.into()),
+            body,
+            ..Default::default()
+        }
+    }
+
+    /// Successful reply to a method call
+    pub fn method_return(call: &Message, body: Vec<Value>) -> Self {
+        Message {
+            kind: MessageKind::MethodReturn,
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            body,
+            ..Default::default()
+        }
+    }
+
+    /// Error reply to a method call
+    pub fn error(call: &Message, name: &str, text: &str) -> Self {
+        Message {
+            kind: MessageKind::Error,
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            error_name: Some(name.into()),
+            body: vec![Value::String(text.into())],
+            ..Default::default()
+        }
+    }
+
+    /// Signature of the whole body
+    pub fn signature(&self) -> String {
+        self.body.iter().map(Value::signature).collect()
+    }
+
+    /// Serialise as little endian
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Encoder::default();
+        for value in &self.body {
+            body.value(value);
+        }
+
+        let mut fields = Vec::new();
+        let mut field = |code: u8, value: Value| {
+            fields.push(Value::Struct(vec![
+                Value::Byte(code),
+                Value::Variant(Box::new(value)),
+            ]))
+        };
+        if let Some(path) = &self.path {
+            field(1, Value::ObjectPath(path.clone()));
+        }
+        if let Some(interface) = &self.interface {
+            field(2, Value::String(interface.clone()));
+        }
+        if let Some(member) = &self.member {
+            field(3, Value::String(member.clone()));
+        }
+        if let Some(error_name) = &self.error_name {
+            field(4, Value::String(error_name.clone()));
+        }
+        if let Some(reply_serial) = self.reply_serial {
+            field(5, Value::UInt32(reply_serial));
+        }
+        if let Some(destination) = &self.destination {
+            field(6, Value::String(destination.clone()));
+        }
+        if let Some(sender) = &self.sender {
+            field(7, Value::String(sender.clone()));
+        }
+        if !self.body.is_empty() {
+            field(8, Value::Signature(self.signature()));
+        }
+
+        let mut message = Encoder::default();
+        message.buf.extend([b'l', self.kind as u8, self.flags, 1]);
+        message.u32(body.buf.len() as u32);
+        message.u32(self.serial);
+        message.value(&Value::Array("(yv)".into(), fields));
+        message.pad(8);
+        message.buf.extend(body.buf);
+        message.buf
+    }
+
+    /// Length of the message starting at `buf`, once enough of it has arrived to tell
+    fn encoded_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+        if buf.len() < 16 {
+            return Ok(None);
+        }
+        let u32_at = |i: usize| {
+            let bytes = buf[i..i + 4].try_into().unwrap();
+            match buf[0] {
+                b'l' => Ok(u32::from_le_bytes(bytes)),
+                b'B' => Ok(u32::from_be_bytes(bytes)),
+                e => Err(Error::Protocol(format!("invalid endianness {e:#x}"))),
+            }
+        };
+        let body = u32_at(4)? as usize;
+        let fields = u32_at(12)? as usize;
+        let len = (16 + fields).next_multiple_of(8) + body;
+        if len > MAX_MESSAGE {
+            return Err(Error::Protocol(format!("message too long: {len} bytes")));
+        }
+        Ok(Some(len))
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut decoder = Decoder {
+            buf,
+            pos: 0,
+            big_endian: buf[0] == b'B',
+        };
+        let header = decoder.value("(yyyyuua(yv))")?;
+        let Value::Struct(header) = header else {
+            unreachable!()
+        };
+        let [Value::Byte(_), Value::Byte(kind), Value::Byte(flags), Value::Byte(version), Value::UInt32(_), Value::UInt32(serial), Value::Array(_, fields)] =
+            &header[..]
+        else {
+            unreachable!()
+        };
+        if *version != 1 {
+            return Err(Error::Protocol(format!("unsupported version {version}")));
+        }
+        let kind = match kind {
+            1 => MessageKind::MethodCall,
+            2 => MessageKind::MethodReturn,
+            3 => MessageKind::Error,
+            4 => MessageKind::Signal,
+            k => return Err(Error::Protocol(format!("unknown message type {k}"))),
+        };
+        let mut message = Message {
+            kind,
+            flags: *flags,
+            serial: *serial,
+            ..Default::default()
+        };
+        let mut signature = String::new();
+        for field in fields {
+            let Some([Value::Byte(code), Value::Variant(value)]) = field.as_slice() else {
+                unreachable!()
+            };
+            let string = || value.as_str().map(String::from);
+            match code {
+                1 => message.path = string(),
+                2 => message.interface = string(),
+                3 => message.member = string(),
+                4 => message.error_name = string(),
+                5 => message.reply_serial = value.as_u32(),
+                6 => message.destination = string(),
+                7 => message.sender = string(),
+                8 => signature = string().unwrap_or_default(),
+                _ => {}
+            }
+        }
+
+        decoder.align(8)?;
+        let mut rest = signature.as_str();
+        while !rest.is_empty() {
+            let (single, tail) = split_type(rest)?;
+            message.body.push(decoder.value(single)?);
+            rest = tail;
+        }
+        Ok(message)
+    }
+}
+
+/// Split the first complete type off a signature
+fn split_type(signature: &str) -> Result<(&str, &str), Error> {
+    let bad = || Error::Protocol(format!("invalid signature {signature:?}"));
+    let len = match signature.as_bytes().first().ok_or_else(bad)? {
+        b'a' => 1 + split_type(&signature[1..])?.0.len(),
+        open @ (b'(' | b'{') => {
+            let close = if *open == b'(' { b')' } else { b'}' };
+            let mut depth = 0;
+            signature
+                .bytes()
+                .position(|c| {
+                    if c == *open {
+                        depth += 1;
+                    } else if c == close {
+                        depth -= 1;
+                    }
+                    depth == 0
+                })
+                .ok_or_else(bad)?
+                + 1
+        }
+        _ => 1,
+    };
+    Ok(signature.split_at(len))
+}
+
+/// Alignment of a type, given its signature
+fn alignment(signature: &str) -> usize {
+    match signature.as_bytes().first() {
+        Some(b'n' | b'q') => 2,
+        Some(b'b' | b'i' | b'u' | b'h' | b's' | b'o' | b'a') => 4,
+        Some(b'x' | b't' | b'd' | b'(' | b'{') => 8,
+        _ => 1,
+    }
+}
+
+/// Marshals values, little endian
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn pad(&mut self, align: usize) {
+        self.buf.resize(self.buf.len().next_multiple_of(align), 0);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.pad(4);
+        self.buf.extend(n.to_le_bytes());
+    }
+
+    fn value(&mut self, value: &Value) {
+        self.pad(alignment(&value.signature()));
+        match value {
+            Value::Byte(n) => self.buf.push(*n),
+            Value::Bool(b) => self.u32(u32::from(*b)),
+            Value::Int16(n) => self.buf.extend(n.to_le_bytes()),
+            Value::UInt16(n) => self.buf.extend(n.to_le_bytes()),
+            Value::Int32(n) => self.buf.extend(n.to_le_bytes()),
+            Value::UInt32(n) | Value::UnixFd(n) => self.buf.extend(n.to_le_bytes()),
+            Value::Int64(n) => self.buf.extend(n.to_le_bytes()),
+            Value::UInt64(n) => self.buf.extend(n.to_le_bytes()),
+            Value::Double(n) => self.buf.extend(n.to_le_bytes()),
+            Value::String(s) | Value::ObjectPath(s) => {
+                self.u32(s.len() as u32);
+                self.buf.extend(s.as_bytes());
+                self.buf.push(0);
+            }
+            Value::Signature(s) => {
+                self.buf.push(s.len() as u8);
+                self.buf.extend(s.as_bytes());
+                self.buf.push(0);
+            }
+            Value::Array(element, items) => {
+                let len_at = self.buf.len();
+                self.u32(0);
+                self.pad(alignment(element));
+                let start = self.buf.len();
+                for item in items {
+                    self.value(item);
+                }
+                let len = (self.buf.len() - start) as u32;
+                self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+            }
+            Value::Struct(fields) => {
+                for field in fields {
+                    self.value(field);
+                }
+            }
+            Value::DictEntry(key, value) => {
+                self.value(key);
+                self.value(value);
+            }
+            Value::Variant(value) => {
+                self.value(&Value::Signature(value.signature()));
+                self.value(value);
+            }
+        }
+    }
+}
+
+/// Unmarshals values
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Decoder<'a> {
+    fn align(&mut self, align: usize) -> Result<(), Error> {
+        self.take(self.pos.next_multiple_of(align) - self.pos)?;
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| Error::Protocol("truncated message".into()))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        self.align(N)?;
+        let mut bytes: [u8; N] = self.take(N)?.try_into().unwrap();
+        if self.big_endian {
+            bytes.reverse();
+        }
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        self.fixed().map(u32::from_le_bytes)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, Error> {
+        let bytes = self.take(len + 1)?;
+        String::from_utf8(bytes[..len].to_vec())
+            .map_err(|_| Error::Protocol("invalid UTF-8 in string".into()))
+    }
+
+    /// Decode a single complete type
+    fn value(&mut self, signature: &str) -> Result<Value, Error> {
+        let bad = || Error::Protocol(format!("unsupported signature {signature:?}"));
+        Ok(match signature.as_bytes().first().ok_or_else(bad)? {
+            b'y' => Value::Byte(self.take(1)?[0]),
+            b'b' => Value::Bool(self.u32()? != 0),
+            b'n' => Value::Int16(i16::from_le_bytes(self.fixed()?)),
+            b'q' => Value::UInt16(u16::from_le_bytes(self.fixed()?)),
+            b'i' => Value::Int32(i32::from_le_bytes(self.fixed()?)),
+            b'u' => Value::UInt32(self.u32()?),
+            b'h' => Value::UnixFd(self.u32()?),
+            b'x' => Value::Int64(i64::from_le_bytes(self.fixed()?)),
+            b't' => Value::UInt64(u64::from_le_bytes(self.fixed()?)),
+            b'd' => Value::Double(f64::from_le_bytes(self.fixed()?)),
+            b's' => {
+                let len = self.u32()? as usize;
+                Value::String(self.string(len)?)
+            }
+            b'o' => {
+                let len = self.u32()? as usize;
+                Value::ObjectPath(self.string(len)?)
+            }
+            b'g' => {
+                let len = self.take(1)?[0] as usize;
+                Value::Signature(self.string(len)?)
+            }
+            b'v' => {
+                let len = self.take(1)?[0] as usize;
+                let signature = self.string(len)?;
+                let (single, rest) = split_type(&signature)?;
+                if !rest.is_empty() {
+                    return Err(bad());
+                }
+                Value::Variant(Box::new(self.value(single)?))
+            }
+            b'a' => {
+                let len = self.u32()? as usize;
+                let element = &signature[1..];
+                self.align(alignment(element))?;
+                let end = self.pos + len;
+                if end > self.buf.len() {
+                    return Err(Error::Protocol("truncated array".into()));
+                }
+                let mut items = Vec::new();
+                while self.pos < end {
+                    items.push(self.value(element)?);
+                }
+                Value::Array(element.into(), items)
+            }
+            b'(' | b'{' => {
+                self.align(8)?;
+                let mut fields = Vec::new();
+                let mut rest = &signature[1..signature.len() - 1];
+                while !rest.is_empty() {
+                    let (single, tail) = split_type(rest)?;
+                    fields.push(self.value(single)?);
+                    rest = tail;
+                }
+                if signature.starts_with('(') {
+                    Value::Struct(fields)
+                } else {
+                    let [key, value] = <[Value; 2]>::try_from(fields).map_err(|_| bad())?;
+                    Value::DictEntry(Box::new(key), Box::new(value))
+                }
+            }
+            _ => return Err(bad()),
+        })
+    }
+}
+
+/// A connection to a message bus
+pub struct Connection {
+    stream: UnixStream,
+    writer: Writer,
+    /// Bytes read but not yet parsed into a message
+    buf: Vec<u8>,
+    /// Messages that arrived while waiting for a reply
+    queue: VecDeque<Message>,
+    unique_name: String,
+}
+
+/// Sends messages on a connection, from any thread
+#[derive(Clone)]
+pub struct Writer(Arc<Mutex<Outgoing>>);
+
+struct Outgoing {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl Writer {
+    /// Send a message, returning its serial
+    pub fn send(&self, mut message: Message) -> Result<u32, Error> {
+        let mut outgoing = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        outgoing.serial = outgoing.serial.wrapping_add(1).max(1);
+        message.serial = outgoing.serial;
+        outgoing.stream.write_all(&message.encode())?;
+        Ok(message.serial)
+    }
+}
+
+impl Connection {
+    /// Connect to the system bus
+    pub fn system() -> Result<Self, Error> {
+        let address = std::env::var("DBUS_SYSTEM_BUS_ADDRESS");
+        Connection::open(address.as_deref().unwrap_or(SYSTEM_BUS))
+    }
+
+    /// Connect to the session bus
+    pub fn session() -> Result<Self, Error> {
+        let address = std::env::var("DBUS_SESSION_BUS_ADDRESS")
+            .map_err(|_| Error::Address("DBUS_SESSION_BUS_ADDRESS isn't set".into()))?;
+        Connection::open(&address)
+    }
+
+    /// Connect to the bus at a D-Bus address such as `unix:path=/run/dbus/system_bus_socket`
+    pub fn open(address: &str) -> Result<Self, Error> {
+        let mut last_error = Error::Address(format!("no usable address in {address:?}"));
+        for address in address.split(';') {
+            match connect(address) {
+                Ok(stream) => return Connection::start(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Authenticate and say Hello
+    fn start(mut stream: UnixStream) -> Result<Self, Error> {
+        let uid = crate::sys::uid();
+        let hex_uid: String = uid
+            .to_string()
+            .bytes()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        stream.write_all(format!("\0AUTH EXTERNAL {hex_uid}\r\n").as_bytes())?;
+        let reply = read_auth_line(&mut stream)?;
+        if !reply.starts_with("OK ") {
+            return Err(Error::Auth(reply));
+        }
+        stream.write_all(b"BEGIN\r\n")?;
+
+        let writer = Writer(Arc::new(Mutex::new(Outgoing {
+            stream: stream.try_clone()?,
+            serial: 0,
+        })));
+        let mut connection = Connection {
+            stream,
+            writer,
+            buf: Vec::new(),
+            queue: VecDeque::new(),
+            unique_name: String::new(),
+        };
+        let reply = connection.bus_call("Hello", vec![])?;
+        connection.unique_name = reply
+            .first()
+            .and_then(Value::as_str)
+            .ok_or_else(|| Error::Protocol("Hello didn't return a name".into()))?
+            .into();
+        Ok(connection)
+    }
+
+    /// Send a message, returning its serial
+    pub fn send(&mut self, message: Message) -> Result<u32, Error> {
+        self.writer.send(message)
+    }
+
+    /// Something to send messages with from other threads, while this one waits for them
+    pub fn writer(&self) -> Writer {
+        self.writer.clone()
+    }
+
+    /// Send a method call and wait for its reply.
+    /// Error replies are returned as `Error::Remote`.
+    pub fn call(&mut self, message: Message) -> Result<Message, Error> {
+        let serial = self.send(message)?;
+        let deadline = Instant::now() + CALL_TIMEOUT;
+        loop {
+            let message = self
+                .read(Some(deadline))?
+                .ok_or_else(|| Error::Io(io::ErrorKind::TimedOut.into()))?;
+            if message.reply_serial != Some(serial) {
+                self.queue.push_back(message);
+                continue;
+            }
+            return match message.kind {
+                MessageKind::Error => Err(Error::Remote {
+                    name: message.error_name.unwrap_or_default(),
+                    message: message
+                        .body
+                        .first()
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .into(),
+                }),
+                _ => Ok(message),
+            };
+        }
+    }
+
+    /// Call a method, returning the reply's body
+    pub fn method_call(
+        &mut self,
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        body: Vec<Value>,
+    ) -> Result<Vec<Value>, Error> {
+        let call = Message::method_call(destination, path, interface, member, body);
+        Ok(self.call(call)?.body)
+    }
+
+    /// Call a method on the bus itself
+    pub fn bus_call(&mut self, member: &str, body: Vec<Value>) -> Result<Vec<Value>, Error> {
+        self.method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            member,
+            body,
+        )
+    }
+
+    /// Take ownership of a well known name, failing if someone else has it
+    pub fn request_name(&mut self, name: &str) -> Result<(), Error> {
+        // DBUS_NAME_FLAG_DO_NOT_QUEUE
+        let flags = Value::UInt32(4);
+        let reply = self.bus_call("RequestName", vec![Value::String(name.into()), flags])?;
+        match reply.first().and_then(Value::as_u32) {
+            // Primary owner, or already the owner
+            Some(1 | 4) => Ok(()),
+            _ => Err(Error::NameTaken(name.into())),
+        }
+    }
+
+    /// Wait for the next incoming message, up to `timeout` if given
+    pub fn recv(&mut self, timeout: Option<Duration>) -> Result<Option<Message>, Error> {
+        if let Some(message) = self.queue.pop_front() {
+            return Ok(Some(message));
+        }
+        self.read(timeout.map(|timeout| Instant::now() + timeout))
+    }
+
+    /// Read the next message off the socket
+    fn read(&mut self, deadline: Option<Instant>) -> Result<Option<Message>, Error> {
+        loop {
+            if let Some(len) = Message::encoded_len(&self.buf)? {
+                if self.buf.len() >= len {
+                    let message = Message::decode(&self.buf[..len]);
+                    self.buf.drain(..len);
+                    return message.map(Some);
+                }
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(timeout) if !timeout.is_zero() => Some(timeout),
+                    _ => return Ok(None),
+                },
+                None => None,
+            };
+            self.stream.set_read_timeout(timeout)?;
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(Error::Io(io::ErrorKind::UnexpectedEof.into())),
+                Ok(n) => self.buf.extend(&chunk[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Connect to a single `unix:` address
+fn connect(address: &str) -> Result<UnixStream, Error> {
+    let bad = || Error::Address(format!("unsupported address {address:?}"));
+    let params = address.strip_prefix("unix:").ok_or_else(bad)?;
+    for param in params.split(',') {
+        let (key, value) = param.split_once('=').ok_or_else(bad)?;
+        let value = unescape(value).ok_or_else(bad)?;
+        match key {
+            "path" => return Ok(UnixStream::connect(OsStr::from_bytes(&value))?),
+            "abstract" => {
+                let addr = SocketAddr::from_abstract_name(&value)?;
+                return Ok(UnixStream::connect_addr(&addr)?);
+            }
+            _ => {}
+        }
+    }
+    Err(bad())
+}
+
+/// Undo the %xx escaping in address values
+fn unescape(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut chars = value.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    Some(bytes)
+}
+
+/// Read one CRLF terminated line of the auth conversation
+fn read_auth_line(stream: &mut UnixStream) -> Result<String, Error> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(Error::Auth(
+                "connection closed during authentication".into(),
+            ));
+        }
+        line.push(byte[0]);
+        if line.len() > 4096 {
+            return Err(Error::Auth("authentication line too long".into()));
+        }
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// D-Bus failures
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The bus address couldn't be used
+    Address(String),
+    /// The bus rejected our credentials
+    Auth(String),
+    /// The other end sent something we couldn't understand
+    Protocol(String),
+    /// Someone else owns the name
+    NameTaken(String),
+    /// The method call returned an error
+    Remote {
+        name: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Address(e) => write!(f, "bus address: {e}"),
+            Error::Auth(e) => write!(f, "authentication failed: {e}"),
+            Error::Protocol(e) => write!(f, "protocol error: {e}"),
+            Error::NameTaken(name) => write!(f, "{name} is already owned"),
+            Error::Remote { name, message } => write!(f, "{name}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A private dbus-daemon for tests
+#[cfg(test)]
+pub mod test_bus {
+    use crate::test_dir::TempDir;
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+
+    pub struct TestBus {
+        daemon: Child,
+        // Removed after the daemon's been killed
+        _dir: TempDir,
+        pub address: String,
+    }
+
+    impl TestBus {
+        /// Start a bus, or None if dbus-daemon isn't installed
+        pub fn start() -> Option<Self> {
+            let dir = TempDir::new("bus");
+            let config = dir.path().join("bus.conf");
+            std::fs::write(
+                &config,
+                format!(
+                    r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:path={}/bus</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#,
+                    dir.path().display()
+                ),
+            )
+            .unwrap();
+
+            let Ok(mut daemon) = Command::new("dbus-daemon")
+                .arg(format!("--config-file={}", config.display()))
+                .args(["--nofork", "--nopidfile", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+            else {
+                eprintln!("dbus-daemon not available, skipping");
+                return None;
+            };
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Some(TestBus {
+                daemon,
+                _dir: dir,
+                address: address.trim().into(),
+            })
+        }
+    }
+
+    impl Drop for TestBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+}
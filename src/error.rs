@@ -0,0 +1,135 @@
+//! Why the daemon couldn't start, or stopped
+
+use crate::{config, control, inhibit, settings};
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Bad command line arguments
+    Usage(String),
+    /// The config file or command line settings are wrong
+    Config(config::Error),
+    /// xscreensaver's settings couldn't be read
+    Settings(settings::Error),
+    /// xscreensaver never turns the screen off, so there's never a time to sleep
+    DpmsDisabled,
+    /// HOME isn't set, so ~/.xscreensaver can't be found
+    NoHome,
+    /// The thread following xscreensaver stopped
+    WatcherLost,
+    /// An inhibit couldn't be added, removed or listed
+    Inhibit(inhibit::Error),
+    /// The daemon couldn't be talked to, or there's already one running
+    Control(control::Error),
+    /// The command to inhibit sleep while it runs couldn't be started
+    Spawn(String, io::Error),
+}
+
+/// What `status` exits with when the daemon isn't running, like `systemctl status`'s 3 but
+/// distinct from every error
+pub const NOT_RUNNING: u8 = 10;
+
+impl Error {
+    /// What to exit with, so scripts can tell what went wrong
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Usage(_) => 2,
+            Error::Config(_) => 3,
+            Error::Settings(_) => 4,
+            Error::DpmsDisabled => 5,
+            Error::NoHome => 6,
+            Error::WatcherLost => 7,
+            Error::Inhibit(_) => 8,
+            Error::Control(_) => 9,
+            // What shells exit with
+            Error::Spawn(_, e) if e.kind() == io::ErrorKind::NotFound => 127,
+            Error::Spawn(..) => 126,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(message) => write!(f, "{message}"),
+            Error::Config(e) => write!(f, "{e}"),
+            Error::Settings(e @ settings::Error::NotFound(_)) => write!(
+                f,
+                "{e}. Run xscreensaver-settings to create ~/.xscreensaver"
+            ),
+            Error::Settings(e) => write!(f, "Can't read xscreensaver's settings: {e}"),
+            Error::DpmsDisabled => write!(
+                f,
+                "DPMS isn't enabled in xscreensaver's settings. Turn on Display Power Management \
+                 in xscreensaver-settings, or set `dpmsEnabled: True` in ~/.xscreensaver"
+            ),
+            Error::NoHome => write!(f, "HOME isn't set, so ~/.xscreensaver can't be found"),
+            Error::WatcherLost => write!(f, "Lost track of xscreensaver"),
+            Error::Inhibit(e) => write!(f, "{e}"),
+            Error::Control(e) => write!(f, "{e}"),
+            Error::Spawn(command, e) => write!(f, "Couldn't run {command}: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(e) => Some(e),
+            Error::Settings(e) => Some(e),
+            Error::Inhibit(e) => Some(e),
+            Error::Control(e) => Some(e),
+            Error::Spawn(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(e: config::Error) -> Self {
+        Error::Config(e)
+    }
+}
+
+impl From<settings::Error> for Error {
+    fn from(e: settings::Error) -> Self {
+        Error::Settings(e)
+    }
+}
+
+impl From<inhibit::Error> for Error {
+    fn from(e: inhibit::Error) -> Self {
+        Error::Inhibit(e)
+    }
+}
+
+impl From<control::Error> for Error {
+    fn from(e: control::Error) -> Self {
+        Error::Control(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let errors = [
+            Error::Usage(String::new()),
+            Error::Config(config::Error::Invalid(String::new())),
+            Error::Settings(settings::Error::NotFound(Vec::new())),
+            Error::DpmsDisabled,
+            Error::NoHome,
+            Error::WatcherLost,
+            Error::Inhibit(inhibit::Error::NoStateDir),
+            Error::Control(control::Error::NotRunning),
+            Error::Spawn(String::new(), io::ErrorKind::NotFound.into()),
+            Error::Spawn(String::new(), io::ErrorKind::PermissionDenied.into()),
+        ];
+        let codes: BTreeSet<u8> = errors.iter().map(Error::exit_code).collect();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&NOT_RUNNING));
+    }
+}
@@ -0,0 +1,244 @@
+//! Parse the events `xscreensaver-command -watch` prints, e.g. `LOCK Fri Nov 13 17:39:59 2020`
+
+use std::{fmt, str::FromStr};
+
+/// An event printed by `xscreensaver-command -watch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The screen blanked, possibly without locking
+    Blank(Timestamp),
+    /// The screen unblanked, which also means it was unlocked
+    Unblank(Timestamp),
+    /// The screen locked
+    Lock(Timestamp),
+    /// A new display hack started
+    Run { hack: u32 },
+    /// Display hacks were throttled
+    Throttle(Timestamp),
+    /// Display hacks are no longer throttled
+    Unthrottle(Timestamp),
+}
+
+impl FromStr for Event {
+    type Err = ParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let timestamp = || rest.parse::<Timestamp>();
+        match kind {
+            "BLANK" => Ok(Event::Blank(timestamp()?)),
+            "UNBLANK" => Ok(Event::Unblank(timestamp()?)),
+            "LOCK" => Ok(Event::Lock(timestamp()?)),
+            "THROTTLE" => Ok(Event::Throttle(timestamp()?)),
+            "UNTHROTTLE" => Ok(Event::Unthrottle(timestamp()?)),
+            "RUN" => rest
+                .trim()
+                .parse()
+                .map(|hack| Event::Run { hack })
+                .map_err(|_| ParseError::BadHack(rest.trim().to_string())),
+            "" => Err(ParseError::Empty),
+            _ => Err(ParseError::UnknownEvent(line.to_string())),
+        }
+    }
+}
+
+/// Local time of an event, as printed by `ctime(3)`: `Fri Nov 13 17:39:59 2020`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl FromStr for Timestamp {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || ParseError::BadTimestamp(s.trim().to_string());
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [weekday, month, day, time, year] = fields[..] else {
+            return Err(if fields.is_empty() {
+                ParseError::MissingTimestamp
+            } else {
+                bad()
+            });
+        };
+        const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        if !WEEKDAYS.contains(&weekday) {
+            return Err(bad());
+        }
+        let month = MONTHS.iter().position(|m| *m == month).ok_or_else(bad)? as u8 + 1;
+        let mut hms = time.split(':').map(|n| n.parse::<u8>());
+        let (Some(Ok(hour)), Some(Ok(minute)), Some(Ok(second)), None) =
+            (hms.next(), hms.next(), hms.next(), hms.next())
+        else {
+            return Err(bad());
+        };
+        let timestamp = Timestamp {
+            year: year.parse().map_err(|_| bad())?,
+            month,
+            day: day.parse().map_err(|_| bad())?,
+            hour,
+            minute,
+            second,
+        };
+        if !(1..=31).contains(&timestamp.day) || hour > 23 || minute > 59 || second > 60 {
+            return Err(bad());
+        }
+        Ok(timestamp)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Why a line from `xscreensaver-command -watch` couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The line was blank
+    Empty,
+    /// The line didn't start with a known event name
+    UnknownEvent(String),
+    /// The event should have been followed by a timestamp
+    MissingTimestamp,
+    /// The timestamp wasn't in `ctime(3)` format
+    BadTimestamp(String),
+    /// RUN wasn't followed by a hack number
+    BadHack(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty line"),
+            ParseError::UnknownEvent(line) => write!(f, "unknown event: {line:?}"),
+            ParseError::MissingTimestamp => write!(f, "missing timestamp"),
+            ParseError::BadTimestamp(s) => write!(f, "invalid timestamp: {s:?}"),
+            ParseError::BadHack(s) => write!(f, "invalid hack number: {s:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRI_NOV_13: Timestamp = Timestamp {
+        year: 2020,
+        month: 11,
+        day: 13,
+        hour: 17,
+        minute: 39,
+        second: 59,
+    };
+
+    fn parse(line: &str) -> Result<Event, ParseError> {
+        line.parse()
+    }
+
+    #[test]
+    fn parses_each_event() {
+        let at = " Fri Nov 13 17:39:59 2020";
+        assert_eq!(parse(&format!("BLANK{at}")), Ok(Event::Blank(FRI_NOV_13)));
+        assert_eq!(
+            parse(&format!("UNBLANK{at}")),
+            Ok(Event::Unblank(FRI_NOV_13))
+        );
+        assert_eq!(parse(&format!("LOCK{at}\n")), Ok(Event::Lock(FRI_NOV_13)));
+        assert_eq!(
+            parse(&format!("THROTTLE{at}")),
+            Ok(Event::Throttle(FRI_NOV_13))
+        );
+        assert_eq!(
+            parse(&format!("UNTHROTTLE{at}")),
+            Ok(Event::Unthrottle(FRI_NOV_13))
+        );
+        assert_eq!(parse("RUN 34"), Ok(Event::Run { hack: 34 }));
+        assert_eq!(parse("RUN  0 "), Ok(Event::Run { hack: 0 }));
+    }
+
+    #[test]
+    fn parses_space_padded_days() {
+        let timestamp: Timestamp = "Mon Feb  1 09:05:00 2021".parse().unwrap();
+        assert_eq!(
+            timestamp,
+            Timestamp {
+                year: 2021,
+                month: 2,
+                day: 1,
+                hour: 9,
+                minute: 5,
+                second: 0,
+            }
+        );
+        assert_eq!(timestamp.to_string(), "2021-02-01 09:05:00");
+        assert_eq!(
+            parse("LOCK Mon Feb  1 09:05:00 2021"),
+            Ok(Event::Lock(timestamp))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        assert_eq!(parse("   \n"), Err(ParseError::Empty));
+        assert_eq!(
+            parse("EXPLODE Fri Nov 13 17:39:59 2020"),
+            Err(ParseError::UnknownEvent(
+                "EXPLODE Fri Nov 13 17:39:59 2020".into()
+            ))
+        );
+        assert_eq!(
+            parse("lock Fri Nov 13 17:39:59 2020")
+                .unwrap_err()
+                .to_string(),
+            "unknown event: \"lock Fri Nov 13 17:39:59 2020\""
+        );
+        assert_eq!(parse("LOCK"), Err(ParseError::MissingTimestamp));
+        assert_eq!(parse("RUN"), Err(ParseError::BadHack(String::new())));
+        assert_eq!(parse("RUN x"), Err(ParseError::BadHack("x".into())));
+        assert_eq!(parse("RUN -1"), Err(ParseError::BadHack("-1".into())));
+    }
+
+    #[test]
+    fn rejects_bad_timestamps() {
+        for bad in [
+            "Fri Nov 13 17:39:59",
+            "Fri Nov 13 17:39:59 2020 extra",
+            "Fry Nov 13 17:39:59 2020",
+            "Fri November 13 17:39:59 2020",
+            "Fri Nov 0 17:39:59 2020",
+            "Fri Nov 32 17:39:59 2020",
+            "Fri Nov 13 24:00:00 2020",
+            "Fri Nov 13 17:60:00 2020",
+            "Fri Nov 13 17:39:61 2020",
+            "Fri Nov 13 17:39 2020",
+            "Fri Nov 13 17:39:59:00 2020",
+            "Fri Nov 13 17:39:59 MMXX",
+        ] {
+            assert_eq!(
+                parse(&format!("BLANK {bad}")),
+                Err(ParseError::BadTimestamp(bad.into())),
+                "{bad:?}"
+            );
+        }
+        // A leap second
+        assert!("Wed Dec 31 23:59:60 2016".parse::<Timestamp>().is_ok());
+    }
+}
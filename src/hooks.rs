@@ -0,0 +1,287 @@
+//! Hook directories run before sleeping and after waking up, like `run-parts`.
+//!
+//! `pre-suspend.d` runs before sleeping, and any hook in it can veto the sleep by failing.
+//! `post-resume.d` runs after waking up from it.
+//! Hooks are told why and how it's sleeping in their environment.
+
+use crate::{
+    config::format_duration,
+    machine::Trigger,
+    sleep::SleepAction,
+    supervise::{self, Child},
+};
+use std::{
+    fmt, fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    time::Duration,
+};
+
+/// Which hooks to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    PreSuspend,
+    PostResume,
+}
+
+impl Stage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::PreSuspend => "pre-suspend",
+            Stage::PostResume => "post-resume",
+        }
+    }
+}
+
+/// Why and how it's sleeping, given to hooks in their environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Context {
+    pub trigger: Trigger,
+    pub action: SleepAction,
+    /// How long the screen had been locked
+    pub locked_for: Duration,
+    /// How many times it had already slept since locking
+    pub sleeps: u32,
+}
+
+impl Context {
+    fn env(&self, stage: Stage) -> Vec<(&'static str, String)> {
+        vec![
+            ("XSCREENSAVER_SUSPEND_STAGE", stage.as_str().into()),
+            ("XSCREENSAVER_SUSPEND_TRIGGER", self.trigger.as_str().into()),
+            ("XSCREENSAVER_SUSPEND_ACTION", self.action.to_string()),
+            (
+                "XSCREENSAVER_SUSPEND_LOCKED_FOR",
+                self.locked_for.as_secs().to_string(),
+            ),
+            ("XSCREENSAVER_SUSPEND_SLEEPS", self.sleeps.to_string()),
+        ]
+    }
+}
+
+/// The hook directories in one place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hooks {
+    dir: PathBuf,
+    /// Longest each hook can run before it's killed
+    timeout: Duration,
+}
+
+impl Hooks {
+    pub fn new(dir: impl Into<PathBuf>, timeout: Duration) -> Self {
+        Hooks {
+            dir: dir.into(),
+            timeout,
+        }
+    }
+
+    /// The hooks for `stage` in the order they run: executable files in `<stage>.d` named with
+    /// only letters, digits, `_` and `-`, sorted by name. Anything else, such as an editor's
+    /// backup, is skipped the same as by `run-parts`.
+    pub fn list(&self, stage: Stage) -> Vec<PathBuf> {
+        let dir = self.dir.join(format!("{}.d", stage.as_str()));
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+            Err(e) => {
+                eprintln!("Couldn't read {}: {e}", dir.display());
+                return Vec::new();
+            }
+        };
+        let mut hooks: Vec<_> = entries
+            .flatten()
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            })
+            .map(|entry| entry.path())
+            .filter(|path| {
+                // Following symlinks
+                fs::metadata(path).is_ok_and(|metadata| {
+                    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+                })
+            })
+            .collect();
+        hooks.sort();
+        hooks
+    }
+
+    /// Run the pre-suspend hooks in turn. The first to fail or not run at all vetoes sleeping,
+    /// and the rest aren't run. One that times out is killed, but doesn't veto it, so a hook
+    /// that hangs can't keep the system awake.
+    pub fn pre_suspend(&self, context: &Context) -> Result<(), Error> {
+        let env = context.env(Stage::PreSuspend);
+        for hook in self.list(Stage::PreSuspend) {
+            match self.run(&hook, &env) {
+                Ok(()) => {}
+                Err(e @ Error::TimedOut(..)) => eprintln!("{e}"),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Run every post-resume hook, after being asleep for `asleep`
+    pub fn post_resume(&self, context: &Context, asleep: Duration) {
+        let mut env = context.env(Stage::PostResume);
+        env.push(("XSCREENSAVER_SUSPEND_ASLEEP", asleep.as_secs().to_string()));
+        for hook in self.list(Stage::PostResume) {
+            if let Err(e) = self.run(&hook, &env) {
+                eprintln!("{e}");
+            }
+        }
+    }
+
+    /// Run a hook to completion, killing it if it takes longer than the timeout
+    fn run(&self, hook: &Path, env: &[(&str, String)]) -> Result<(), Error> {
+        println!("Running {}", hook.display());
+        let child = Child::spawn(
+            Command::new(hook)
+                .envs(env.iter().map(|(key, value)| (key, value)))
+                .stdin(Stdio::null()),
+        )
+        .map_err(|e| Error::Io(hook.into(), e))?;
+        match child.wait(Some(self.timeout)) {
+            Ok(exit) if exit.status.success() => Ok(()),
+            Ok(exit) => Err(Error::Failed(hook.into(), exit.status)),
+            Err(supervise::Error::Io(e)) => Err(Error::Io(hook.into(), e)),
+            Err(supervise::Error::TimedOut(timeout)) => Err(Error::TimedOut(hook.into(), timeout)),
+        }
+    }
+}
+
+/// Why a hook didn't succeed
+#[derive(Debug)]
+pub enum Error {
+    /// It couldn't be run
+    Io(PathBuf, io::Error),
+    /// It exited unsuccessfully
+    Failed(PathBuf, ExitStatus),
+    /// It ran for longer than this, and was killed
+    TimedOut(PathBuf, Duration),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(hook, e) => write!(f, "{}: {e}", hook.display()),
+            Error::Failed(hook, status) => write!(f, "{} failed: {status}", hook.display()),
+            Error::TimedOut(hook, timeout) => write!(
+                f,
+                "{} killed after running for {}",
+                hook.display(),
+                format_duration(*timeout)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+    use std::time::Instant;
+
+    /// A fresh hooks directory
+    fn hooks_dir() -> TempDir {
+        let dir = TempDir::new("hooks");
+        fs::create_dir_all(dir.path().join("pre-suspend.d")).unwrap();
+        fs::create_dir_all(dir.path().join("post-resume.d")).unwrap();
+        dir
+    }
+
+    fn hook(dir: &TempDir, stage: Stage, name: &str, script: &str) -> PathBuf {
+        let path = dir.path().join(format!("{}.d/{name}", stage.as_str()));
+        fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn context() -> Context {
+        Context {
+            trigger: Trigger::ReSuspend,
+            action: SleepAction::Hibernate,
+            locked_for: Duration::from_secs(3600),
+            sleeps: 2,
+        }
+    }
+
+    #[test]
+    fn lists_hooks_like_run_parts() {
+        let dir = hooks_dir();
+        let second = hook(&dir, Stage::PreSuspend, "20-second", "");
+        let first = hook(&dir, Stage::PreSuspend, "10-first", "");
+        hook(&dir, Stage::PreSuspend, "10-first.bak", "");
+        hook(&dir, Stage::PreSuspend, "30-third~", "");
+        fs::write(dir.path().join("pre-suspend.d/40-not-executable"), "").unwrap();
+        let hooks = Hooks::new(dir.path(), Duration::from_secs(5));
+        assert_eq!(hooks.list(Stage::PreSuspend), vec![first, second]);
+        assert_eq!(hooks.list(Stage::PostResume), Vec::<PathBuf>::new());
+        assert_eq!(
+            Hooks::new(dir.path().join("missing"), Duration::from_secs(5)).list(Stage::PreSuspend),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn passes_the_context_in_the_environment() {
+        let dir = hooks_dir();
+        let log = dir.path().join("log");
+        let script = format!(
+            "echo \"$XSCREENSAVER_SUSPEND_STAGE $XSCREENSAVER_SUSPEND_TRIGGER \
+             $XSCREENSAVER_SUSPEND_ACTION $XSCREENSAVER_SUSPEND_LOCKED_FOR \
+             $XSCREENSAVER_SUSPEND_SLEEPS $XSCREENSAVER_SUSPEND_ASLEEP\" >> {}",
+            log.display()
+        );
+        hook(&dir, Stage::PreSuspend, "log", &script);
+        hook(&dir, Stage::PostResume, "log", &script);
+        let hooks = Hooks::new(dir.path(), Duration::from_secs(5));
+        hooks.pre_suspend(&context()).unwrap();
+        hooks.post_resume(&context(), Duration::from_secs(60));
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            "pre-suspend re-suspend hibernate 3600 2 \npost-resume re-suspend hibernate 3600 2 60\n"
+        );
+    }
+
+    #[test]
+    fn failing_hooks_veto_sleeping() {
+        let dir = hooks_dir();
+        let ran = dir.path().join("ran");
+        hook(&dir, Stage::PreSuspend, "10-veto", "exit 1");
+        hook(
+            &dir,
+            Stage::PreSuspend,
+            "20-after",
+            &format!("touch {}", ran.display()),
+        );
+        let hooks = Hooks::new(dir.path(), Duration::from_secs(5));
+        assert!(matches!(
+            hooks.pre_suspend(&context()),
+            Err(Error::Failed(hook, status)) if hook.ends_with("10-veto") && status.code() == Some(1)
+        ));
+        assert!(!ran.exists());
+    }
+
+    #[test]
+    fn kills_hooks_that_take_too_long() {
+        let dir = hooks_dir();
+        hook(&dir, Stage::PreSuspend, "hang", "exec sleep 10");
+        let hooks = Hooks::new(dir.path(), Duration::from_millis(200));
+        let started = Instant::now();
+        assert!(matches!(
+            hooks.run(&hooks.list(Stage::PreSuspend)[0], &[]),
+            Err(Error::TimedOut(..))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // Doesn't veto sleeping
+        hooks.pre_suspend(&context()).unwrap();
+    }
+}
@@ -0,0 +1,542 @@
+//! Things that stop the system sleeping while the screen is locked
+
+use crate::{
+    config::format_duration,
+    login1::{self, Inhibitor, Login1},
+    sleep::SleepAction,
+    toml::{self, Value},
+};
+use std::{
+    fmt,
+    fs::{self, metadata},
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A request not to sleep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inhibit {
+    /// What it's called in `xscreensaver-suspend list`, and removed by with `uninhibit`
+    pub id: String,
+    /// When it stops blocking, or None to block until it's removed
+    pub until: Option<SystemTime>,
+    /// Only blocks while this process is running
+    pub process: Option<Process>,
+    pub reason: String,
+}
+
+impl Inhibit {
+    /// Whether it's still blocking sleep at `now`, and its process is still running
+    pub fn active(&self, now: SystemTime) -> bool {
+        self.until.is_none_or(|until| until > now)
+            && self.process.as_ref().is_none_or(Process::running)
+    }
+
+    /// Parse a record file: optional `until` in seconds since the epoch, `pid` and
+    /// `pid_start` identifying a process, and `reason`
+    pub(crate) fn parse(id: &str, text: &str) -> Result<Self, toml::Error> {
+        let mut inhibit = Inhibit {
+            id: id.into(),
+            until: None,
+            process: None,
+            reason: String::new(),
+        };
+        let (mut pid, mut start_time) = (None, None);
+        for (key, (line, value)) in toml::parse(text)? {
+            let error = |message: &str| toml::Error {
+                line,
+                message: format!("{key} {message}"),
+            };
+            match (key.as_str(), value) {
+                ("until", Value::Integer(secs)) => {
+                    let secs = u64::try_from(secs).map_err(|_| error("can't be negative"))?;
+                    inhibit.until = Some(UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                ("until", _) => return Err(error("should be seconds since 1970")),
+                ("pid", Value::Integer(n)) => {
+                    pid = Some(u32::try_from(n).map_err(|_| error("isn't a process ID"))?);
+                }
+                ("pid", _) => return Err(error("should be a process ID")),
+                ("pid_start", Value::Integer(n)) => {
+                    start_time = Some(u64::try_from(n).map_err(|_| error("can't be negative"))?);
+                }
+                ("pid_start", _) => return Err(error("should be the process start time")),
+                ("reason", Value::String(reason)) => inhibit.reason = reason,
+                ("reason", _) => return Err(error("should be a string")),
+                _ => return Err(error("isn't a known key")),
+            }
+        }
+        inhibit.process = match (pid, start_time) {
+            (Some(pid), Some(start_time)) => Some(Process { pid, start_time }),
+            // Without the start time, it's probably not the process it was, and if it's
+            // already gone, the record's done with
+            (Some(pid), None) => Some(Process::of(pid).unwrap_or(Process::exited(pid))),
+            (None, _) => None,
+        };
+        Ok(inhibit)
+    }
+
+    /// The inverse of `parse`
+    pub(crate) fn to_toml(&self) -> String {
+        let mut text = String::new();
+        if let Some(until) = self.until {
+            let secs = until
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            text += &format!("until = {secs}\n");
+        }
+        if let Some(process) = &self.process {
+            text += &format!(
+                "pid = {}\npid_start = {}\n",
+                process.pid, process.start_time
+            );
+        }
+        text += &format!("reason = {}\n", Value::String(self.reason.clone()));
+        text
+    }
+}
+
+/// e.g. `1 (for another 1h59m): overnight render`
+impl fmt::Display for Inhibit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let left = self.until.map(|until| {
+            let left = until.duration_since(SystemTime::now()).unwrap_or_default();
+            format!("for another {}", format_duration(left))
+        });
+        let process = self
+            .process
+            .as_ref()
+            .map(|process| format!("while pid {} runs", process.pid));
+        match (left, process) {
+            (Some(left), Some(process)) => write!(f, "{} ({left}, {process})", self.id)?,
+            (Some(during), None) | (None, Some(during)) => write!(f, "{} ({during})", self.id)?,
+            (None, None) => write!(f, "{} (until removed)", self.id)?,
+        }
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// A running process, told apart from later ones with the same ID by when it started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Process {
+    pub pid: u32,
+    /// Clock ticks after boot it started at, from /proc/PID/stat
+    pub start_time: u64,
+}
+
+impl Process {
+    /// The process with this ID, if there is one
+    pub fn of(pid: u32) -> Option<Self> {
+        let start_time = start_time(pid)?;
+        Some(Process { pid, start_time })
+    }
+
+    /// A process that's already exited, so is never running
+    pub fn exited(pid: u32) -> Self {
+        Process {
+            pid,
+            // No process started this late
+            start_time: u64::MAX,
+        }
+    }
+
+    /// Whether it's still running, and hasn't exited and had its ID reused
+    pub fn running(&self) -> bool {
+        start_time(self.pid) == Some(self.start_time)
+    }
+
+    /// What it's running, from /proc/PID/comm
+    pub fn name(&self) -> Option<String> {
+        let comm = fs::read_to_string(format!("/proc/{}/comm", self.pid)).ok()?;
+        Some(comm.trim_end().to_string())
+    }
+}
+
+/// When a process started, in clock ticks after boot. None if it's gone, or exited and
+/// waiting to be reaped.
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name in brackets can have spaces and brackets in it, so start after
+    // the last ). Field 3, state, is then the 1st and field 22, starttime, the 20th.
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    if matches!(fields.first(), Some(&"Z" | &"X")) {
+        return None;
+    }
+    fields.get(19)?.parse().ok()
+}
+
+/// A directory of records, one file per inhibit, written by `xscreensaver-suspend inhibit` or
+/// dropped in by other tools under names of their own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InhibitDir {
+    path: PathBuf,
+}
+
+impl InhibitDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        InhibitDir { path: path.into() }
+    }
+
+    /// $XDG_STATE_HOME/xscreensaver-suspend/inhibit.d, if there's a state directory
+    pub fn default_path() -> Option<PathBuf> {
+        let state_home = std::env::var_os("XDG_STATE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(crate::home_dir()?.join(".local/state")))?;
+        Some(state_home.join("xscreensaver-suspend/inhibit.d"))
+    }
+
+    /// The directory at the default path
+    pub fn for_user() -> Result<Self, Error> {
+        Ok(InhibitDir::new(
+            Self::default_path().ok_or(Error::NoStateDir)?,
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Block sleep until `until`, or until removed if None, and only while `process` runs
+    pub fn add(
+        &self,
+        until: Option<SystemTime>,
+        process: Option<Process>,
+        reason: &str,
+    ) -> Result<Inhibit, Error> {
+        let io_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |e| Error::Io(path, e)
+        };
+        fs::create_dir_all(&self.path).map_err(io_error(&self.path))?;
+        let mut inhibit = Inhibit {
+            id: String::new(),
+            until,
+            process,
+            reason: reason.into(),
+        };
+        // Write it somewhere else first, then link it into place under the lowest free ID,
+        // so nothing reads a half written record and two writers can't pick the same ID.
+        // Threads in one process each have their own temp file too.
+        static ADDED: AtomicU64 = AtomicU64::new(0);
+        let temp = self.path.join(format!(
+            ".new-{}-{}",
+            std::process::id(),
+            ADDED.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp, inhibit.to_toml()).map_err(io_error(&temp))?;
+        let mut id = 1u32;
+        let linked = loop {
+            let path = self.path.join(id.to_string());
+            match fs::hard_link(&temp, &path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => id += 1,
+                result => break result.map_err(io_error(&path)),
+            }
+        };
+        let _ = fs::remove_file(&temp);
+        linked?;
+        inhibit.id = id.to_string();
+        Ok(inhibit)
+    }
+
+    /// Remove one record
+    pub fn remove(&self, id: &str) -> Result<(), Error> {
+        if id.is_empty() || id.starts_with('.') || id.contains('/') {
+            return Err(Error::NotFound(id.into()));
+        }
+        let path = self.path.join(id);
+        fs::remove_file(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Error::NotFound(id.into()),
+            _ => Error::Io(path, e),
+        })
+    }
+
+    /// Remove these records, or if there are none, every record `add` made. Other tools'
+    /// records are only removed by name.
+    pub fn uninhibit(&self, ids: &[String]) -> Result<(), Error> {
+        if ids.is_empty() {
+            for inhibit in self.list()? {
+                if inhibit.id.parse::<u32>().is_ok() {
+                    self.remove(&inhibit.id)?;
+                }
+            }
+            return Ok(());
+        }
+        ids.iter().try_for_each(|id| self.remove(id))
+    }
+
+    /// Every record, expired or not, lowest ID first. Records that can't be read are
+    /// logged and skipped, so one bad file doesn't hide the others.
+    pub fn list(&self) -> Result<Vec<Inhibit>, Error> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(self.path.clone(), e)),
+        };
+        let mut inhibits = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::Io(self.path.clone(), e))?;
+            let Some(id) = entry.file_name().to_str().map(String::from) else {
+                continue;
+            };
+            if id.starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            match fs::read_to_string(&path) {
+                Ok(text) => match Inhibit::parse(&id, &text) {
+                    Ok(inhibit) => inhibits.push(inhibit),
+                    Err(e) => eprintln!("Ignoring {}: {e}", path.display()),
+                },
+                // Removed since listing the directory
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("Ignoring {}: {e}", path.display()),
+            }
+        }
+        // Numbered ones first, then other tools' by name
+        inhibits.sort_by_key(|inhibit| {
+            let number = inhibit.id.parse::<u32>().unwrap_or(u32::MAX);
+            (number, inhibit.id.clone())
+        });
+        Ok(inhibits)
+    }
+
+    /// The records still blocking sleep at `now`, removing the ones that have expired or
+    /// whose process has exited
+    pub fn prune(&self, now: SystemTime) -> Result<Vec<Inhibit>, Error> {
+        let (active, inactive): (Vec<_>, Vec<_>) = self
+            .list()?
+            .into_iter()
+            .partition(|inhibit| inhibit.active(now));
+        for inhibit in inactive {
+            // Read it again in case its owner has just renewed it
+            let path = self.path.join(&inhibit.id);
+            let renewed = fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| Inhibit::parse(&inhibit.id, &text).ok());
+            if renewed.is_some_and(|renewed| !renewed.active(now)) {
+                match fs::remove_file(&path) {
+                    Ok(()) => println!("Removed expired inhibit {}", inhibit.id),
+                    Err(e) => eprintln!("Couldn't remove {}: {e}", path.display()),
+                }
+            }
+        }
+        Ok(active)
+    }
+}
+
+/// `touch ~/.no_suspend` to block sleeping for a while. None if HOME isn't set.
+pub fn no_suspend_file() -> Option<PathBuf> {
+    Some(crate::home_dir()?.join(".no_suspend"))
+}
+
+/// ~/.no_suspend, as an inhibit lasting `lifetime` from when it was last modified, or
+/// forever if that's too far away to be a time
+pub fn no_suspend(lifetime: Duration) -> Option<Inhibit> {
+    let modified = metadata(no_suspend_file()?)
+        .and_then(|m| m.modified())
+        .ok()?;
+    Some(Inhibit {
+        id: "~/.no_suspend".into(),
+        until: modified.checked_add(lifetime),
+        process: None,
+        reason: String::new(),
+    })
+}
+
+/// Everything from ~/.no_suspend and `xscreensaver-suspend inhibit` blocking sleep right now
+pub fn active(no_suspend_lifetime: Duration) -> Vec<Inhibit> {
+    let now = SystemTime::now();
+    let mut inhibits: Vec<Inhibit> = no_suspend(no_suspend_lifetime)
+        .into_iter()
+        .filter(|inhibit| inhibit.active(now))
+        .collect();
+    if let Some(dir) = InhibitDir::default_path().map(InhibitDir::new) {
+        match dir.prune(now) {
+            Ok(records) => inhibits.extend(records),
+            Err(e) => eprintln!("Couldn't check inhibitors: {e}"),
+        }
+    }
+    inhibits
+}
+
+/// logind inhibitor locks, e.g. taken with `systemd-inhibit`, that block `action`
+pub fn logind_inhibitors(action: &SleepAction) -> Result<Vec<Inhibitor>, login1::Error> {
+    let mut inhibitors = Login1::system()?.list_inhibitors()?;
+    inhibitors.retain(|inhibitor| inhibitor.blocks(action.inhibited_by()));
+    Ok(inhibitors)
+}
+
+/// Why an inhibit record couldn't be added, removed or listed
+#[derive(Debug)]
+pub enum Error {
+    /// Neither XDG_STATE_HOME nor HOME is set
+    NoStateDir,
+    Io(PathBuf, io::Error),
+    /// There's no inhibit with this ID
+    NotFound(String),
+    /// There's no process with this ID to inhibit while it runs
+    NoProcess(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoStateDir => {
+                write!(f, "nowhere to keep inhibitors, set XDG_STATE_HOME or HOME")
+            }
+            Error::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            Error::NotFound(id) => {
+                write!(f, "no inhibitor {id:?}, see `xscreensaver-suspend list`")
+            }
+            Error::NoProcess(pid) => write!(f, "no process {pid}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    #[test]
+    fn adds_lists_and_removes() {
+        let temp = TempDir::new("inhibit");
+        let dir = InhibitDir::new(temp.path().join("inhibitors"));
+        assert_eq!(dir.list().unwrap(), vec![]);
+
+        let until = UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        let render = dir.add(Some(until), None, "overnight \"render\"").unwrap();
+        let forever = dir.add(None, None, "").unwrap();
+        assert_eq!((render.id.as_str(), forever.id.as_str()), ("1", "2"));
+        assert_eq!(dir.list().unwrap(), vec![render.clone(), forever.clone()]);
+
+        dir.remove("1").unwrap();
+        assert_eq!(dir.list().unwrap(), vec![forever]);
+        // IDs are reused once free
+        assert_eq!(dir.add(None, None, "again").unwrap().id, "1");
+        assert!(matches!(dir.remove("7"), Err(Error::NotFound(id)) if id == "7"));
+        assert!(matches!(dir.remove("../x"), Err(Error::NotFound(_))));
+    }
+
+    #[test]
+    fn adds_from_several_threads_at_once() {
+        let temp = TempDir::new("inhibit");
+        let dir = InhibitDir::new(temp.path());
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let dir = dir.clone();
+                std::thread::spawn(move || dir.add(None, None, "thread").unwrap().id)
+            })
+            .collect();
+        let mut ids: Vec<String> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        ids.sort_by_key(|id| id.parse::<u32>().unwrap());
+        assert_eq!(ids, ["1", "2", "3", "4", "5", "6", "7", "8"]);
+        assert_eq!(dir.list().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn expires() {
+        let now = SystemTime::now();
+        let inhibit = |until| Inhibit {
+            id: "1".into(),
+            until,
+            process: None,
+            reason: String::new(),
+        };
+        assert!(inhibit(None).active(now));
+        assert!(inhibit(Some(now + Duration::from_secs(1))).active(now));
+        assert!(!inhibit(Some(now)).active(now));
+    }
+
+    #[test]
+    fn lasts_while_the_process_runs() {
+        let temp = TempDir::new("inhibit");
+        let dir = InhibitDir::new(temp.path());
+        let mut child = std::process::Command::new("sleep")
+            .arg("60")
+            .spawn()
+            .unwrap();
+        let process = Process::of(child.id()).unwrap();
+        dir.add(None, Some(process.clone()), "sleeping").unwrap();
+
+        let listed = dir.list().unwrap();
+        assert_eq!(listed[0].process, Some(process));
+        assert!(listed[0].active(SystemTime::now()));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!listed[0].active(SystemTime::now()));
+        // Another process that ends up with the same ID doesn't count
+        let reused = Process {
+            pid: std::process::id(),
+            start_time: 0,
+        };
+        assert!(!reused.running());
+    }
+
+    #[test]
+    fn ends_when_a_process_without_a_start_time_has_exited() {
+        // Above the kernel's highest PID, so never running
+        let inhibit = Inhibit::parse("tool", "pid = 4194305\n").unwrap();
+        assert_eq!(inhibit.process, Some(Process::exited(4194305)));
+        assert!(!inhibit.active(SystemTime::now()));
+
+        let running = format!("pid = {}\n", std::process::id());
+        let inhibit = Inhibit::parse("tool", &running).unwrap();
+        assert!(inhibit.active(SystemTime::now()));
+    }
+
+    #[test]
+    fn prunes_expired_records() {
+        let temp = TempDir::new("inhibit");
+        let dir = InhibitDir::new(temp.path());
+        let now = SystemTime::now();
+        let past = now - Duration::from_secs(60);
+        dir.add(Some(past), None, "expired").unwrap();
+        dir.add(None, None, "kept").unwrap();
+        // Other tools use their own names, and needn't say anything at all
+        fs::write(temp.path().join("backup"), "").unwrap();
+        let secs = past.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        fs::write(temp.path().join("render"), format!("until = {secs}\n")).unwrap();
+
+        let ids = |inhibits: Vec<Inhibit>| -> Vec<String> {
+            inhibits.into_iter().map(|inhibit| inhibit.id).collect()
+        };
+        assert_eq!(ids(dir.prune(now).unwrap()), ["2", "backup"]);
+        assert_eq!(ids(dir.list().unwrap()), ["2", "backup"]);
+    }
+
+    #[test]
+    fn uninhibits_its_own_records() {
+        let temp = TempDir::new("inhibit");
+        let dir = InhibitDir::new(temp.path());
+        dir.add(None, None, "one").unwrap();
+        dir.add(None, None, "two").unwrap();
+        fs::write(temp.path().join("backup"), "").unwrap();
+        dir.uninhibit(&[]).unwrap();
+        let ids: Vec<String> = dir.list().unwrap().into_iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec!["backup"]);
+        dir.uninhibit(&["backup".into()]).unwrap();
+        assert_eq!(dir.list().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn skips_bad_records() {
+        let temp = TempDir::new("inhibit");
+        let dir = InhibitDir::new(temp.path());
+        fs::write(temp.path().join("1"), "until = \"tomorrow\"\n").unwrap();
+        fs::write(temp.path().join("2"), "reason = \"ok\"\n").unwrap();
+        let ids: Vec<String> = dir.list().unwrap().into_iter().map(|i| i.id).collect();
+        assert_eq!(ids, vec!["2"]);
+    }
+}
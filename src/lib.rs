@@ -0,0 +1,52 @@
+//! Sleep when xscreensaver has locked the screen for as long as its dpmsOff time.
+//!
+//! The pieces are usable on their own: [`settings`] reads xscreensaver's settings the way
+//! xscreensaver does, [`event`] parses `xscreensaver-command -watch` output, [`machine`]
+//! decides when to sleep, [`inhibit`] checks what's blocking sleep, and [`daemon`] ties
+//! them together.
+
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod dbus;
+mod error;
+pub mod event;
+pub mod hooks;
+pub mod inhibit;
+pub mod login1;
+pub mod machine;
+pub mod notify;
+pub mod reload;
+pub mod service;
+pub mod settings;
+pub mod sleep;
+pub mod status;
+pub mod supervise;
+mod sys;
+#[cfg(test)]
+mod test_dir;
+pub mod toml;
+pub mod verify;
+pub mod watch;
+pub mod xresources;
+
+pub use error::{Error, NOT_RUNNING};
+pub use machine::SuspendStateMachine;
+pub use settings::XscreensaverSettings;
+
+use std::path::PathBuf;
+
+/// $HOME, if it's set
+pub fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// $XDG_RUNTIME_DIR, if it's set
+pub fn runtime_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
@@ -0,0 +1,277 @@
+//! Sleep through systemd-logind's D-Bus API
+
+use crate::dbus::{self, Connection, Value};
+use std::fmt;
+
+pub const DESTINATION: &str = "org.freedesktop.login1";
+pub const PATH: &str = "/org/freedesktop/login1";
+pub const MANAGER: &str = "org.freedesktop.login1.Manager";
+
+/// Errors polkit returns when we aren't authorised
+const DENIED: [&str; 2] = [
+    "org.freedesktop.DBus.Error.AccessDenied",
+    "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired",
+];
+
+/// The logind manager
+pub struct Login1 {
+    conn: Connection,
+}
+
+impl Login1 {
+    /// Talk to logind on the system bus
+    pub fn system() -> Result<Self, Error> {
+        Ok(Login1::new(Connection::system()?))
+    }
+
+    /// Talk to whatever owns org.freedesktop.login1 on `conn`
+    pub fn new(conn: Connection) -> Self {
+        Login1 { conn }
+    }
+
+    /// Ask whether we may call `method`: "yes", "no", "na" or "challenge"
+    pub fn can(&mut self, method: &str) -> Result<String, Error> {
+        let can = format!("Can{method}");
+        let reply = self.call(&can, vec![])?;
+        reply
+            .first()
+            .and_then(Value::as_str)
+            .map(String::from)
+            .ok_or_else(|| {
+                Error::DBus(dbus::Error::Protocol(format!(
+                    "{can} didn't return a string"
+                )))
+            })
+    }
+
+    /// Call Suspend, Hibernate, HybridSleep, SuspendThenHibernate or PowerOff,
+    /// if logind says we're allowed to without authenticating
+    pub fn sleep(&mut self, method: &str) -> Result<(), Error> {
+        match self.can(method)?.as_str() {
+            "yes" => {}
+            "challenge" => return Err(Error::Denied("authentication required".into())),
+            answer => return Err(Error::Unsupported(answer.into())),
+        }
+        // Not interactive, there's nobody to ask for a password
+        self.call(method, vec![Value::Bool(false)])?;
+        Ok(())
+    }
+
+    /// Inhibitor locks currently held
+    pub fn list_inhibitors(&mut self) -> Result<Vec<Inhibitor>, Error> {
+        let reply = self.call("ListInhibitors", vec![])?;
+        let bad = || {
+            Error::DBus(dbus::Error::Protocol(
+                "unexpected ListInhibitors reply".into(),
+            ))
+        };
+        let inhibitors = reply.first().and_then(Value::as_slice).ok_or_else(bad)?;
+        inhibitors
+            .iter()
+            .map(|inhibitor| match inhibitor.as_slice() {
+                Some([what, who, why, mode, uid, pid]) => Some(Inhibitor {
+                    what: what.as_str()?.into(),
+                    who: who.as_str()?.into(),
+                    why: why.as_str()?.into(),
+                    mode: mode.as_str()?.into(),
+                    uid: uid.as_u32()?,
+                    pid: pid.as_u32()?,
+                }),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .ok_or_else(bad)
+    }
+
+    fn call(&mut self, method: &str, body: Vec<Value>) -> Result<Vec<Value>, Error> {
+        self.conn
+            .method_call(DESTINATION, PATH, MANAGER, method, body)
+            .map_err(|e| match e {
+                dbus::Error::Remote { name, message } if DENIED.contains(&name.as_str()) => {
+                    Error::Denied(message)
+                }
+                e => Error::DBus(e),
+            })
+    }
+}
+
+/// A lock taken with logind's Inhibit, e.g. by `systemd-inhibit`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inhibitor {
+    /// Colon separated list of what's inhibited, such as `sleep:shutdown`
+    pub what: String,
+    pub who: String,
+    pub why: String,
+    /// `block` or `delay`
+    pub mode: String,
+    pub uid: u32,
+    pub pid: u32,
+}
+
+impl Inhibitor {
+    /// Does this lock stop `what`, e.g. "sleep", from happening at all
+    pub fn blocks(&self, what: &str) -> bool {
+        self.mode == "block" && self.what.split(':').any(|w| w == what)
+    }
+}
+
+/// Why logind didn't sleep
+#[derive(Debug)]
+pub enum Error {
+    /// logind says this system can't, e.g. "na" when there's no swap to hibernate to
+    Unsupported(String),
+    /// polkit refused
+    Denied(String),
+    DBus(dbus::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Unsupported(answer) => write!(f, "not supported by logind ({answer})"),
+            Error::Denied(message) => write!(f, "not authorised: {message}"),
+            Error::DBus(e) => write!(f, "logind: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<dbus::Error> for Error {
+    fn from(e: dbus::Error) -> Self {
+        Error::DBus(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dbus::{test_bus::TestBus, Message, MessageKind};
+    use std::{sync::mpsc, thread};
+
+    /// Serve a fake logind, reporting the methods called on it
+    fn mock_login1(
+        address: &str,
+        can: &'static str,
+        error: Option<&'static str>,
+    ) -> mpsc::Receiver<String> {
+        let mut conn = Connection::open(address).unwrap();
+        conn.request_name(DESTINATION).unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(Some(call)) = conn.recv(None) {
+                if call.kind != MessageKind::MethodCall || call.path.as_deref() != Some(PATH) {
+                    continue;
+                }
+                let member = call.member.clone().unwrap_or_default();
+                let reply = match (member.as_str(), error) {
+                    (can_method, _) if can_method.starts_with("Can") => {
+                        Message::method_return(&call, vec![Value::String(can.into())])
+                    }
+                    ("ListInhibitors", _) => {
+                        let inhibitor = |what: &str, mode: &str| {
+                            Value::Struct(vec![
+                                Value::String(what.into()),
+                                Value::String("backup".into()),
+                                Value::String("Backing up".into()),
+                                Value::String(mode.into()),
+                                Value::UInt32(1000),
+                                Value::UInt32(42),
+                            ])
+                        };
+                        let inhibitors = vec![
+                            inhibitor("sleep:shutdown", "block"),
+                            inhibitor("sleep", "delay"),
+                        ];
+                        Message::method_return(
+                            &call,
+                            vec![Value::Array("(ssssuu)".into(), inhibitors)],
+                        )
+                    }
+                    (_, Some(error)) => Message::error(&call, error, "Access denied"),
+                    (_, None) => Message::method_return(&call, vec![]),
+                };
+                let _ = tx.send(member);
+                conn.send(reply).unwrap();
+            }
+        });
+        rx
+    }
+
+    fn client(bus: &TestBus) -> Login1 {
+        Login1::new(Connection::open(&bus.address).unwrap())
+    }
+
+    #[test]
+    fn suspends_when_allowed() {
+        let Some(bus) = TestBus::start() else { return };
+        let calls = mock_login1(&bus.address, "yes", None);
+        client(&bus).sleep("Suspend").unwrap();
+        assert_eq!(calls.recv().unwrap(), "CanSuspend");
+        assert_eq!(calls.recv().unwrap(), "Suspend");
+    }
+
+    #[test]
+    fn unsupported_without_calling_suspend() {
+        let Some(bus) = TestBus::start() else { return };
+        let calls = mock_login1(&bus.address, "na", None);
+        let err = client(&bus).sleep("Suspend").unwrap_err();
+        assert!(matches!(err, Error::Unsupported(answer) if answer == "na"));
+        assert_eq!(calls.recv().unwrap(), "CanSuspend");
+        assert!(calls.try_recv().is_err());
+    }
+
+    #[test]
+    fn hibernates_when_allowed() {
+        let Some(bus) = TestBus::start() else { return };
+        let calls = mock_login1(&bus.address, "yes", None);
+        client(&bus).sleep("Hibernate").unwrap();
+        assert_eq!(calls.recv().unwrap(), "CanHibernate");
+        assert_eq!(calls.recv().unwrap(), "Hibernate");
+    }
+
+    #[test]
+    fn lists_inhibitors() {
+        let Some(bus) = TestBus::start() else { return };
+        let _calls = mock_login1(&bus.address, "yes", None);
+        let inhibitors = client(&bus).list_inhibitors().unwrap();
+        assert_eq!(inhibitors.len(), 2);
+        assert_eq!(inhibitors[0].who, "backup");
+        assert_eq!(inhibitors[0].why, "Backing up");
+        assert_eq!((inhibitors[0].uid, inhibitors[0].pid), (1000, 42));
+        assert!(inhibitors[0].blocks("sleep"));
+        assert!(inhibitors[0].blocks("shutdown"));
+        assert!(!inhibitors[0].blocks("idle"));
+        assert!(!inhibitors[1].blocks("sleep"));
+    }
+
+    #[test]
+    fn challenge_is_denied() {
+        let Some(bus) = TestBus::start() else { return };
+        let _calls = mock_login1(&bus.address, "challenge", None);
+        assert!(matches!(
+            client(&bus).sleep("Suspend"),
+            Err(Error::Denied(_))
+        ));
+    }
+
+    #[test]
+    fn polkit_denial_is_reported() {
+        let Some(bus) = TestBus::start() else { return };
+        let _calls = mock_login1(&bus.address, "yes", Some(DENIED[0]));
+        let err = client(&bus).sleep("Suspend").unwrap_err();
+        assert!(matches!(err, Error::Denied(message) if message == "Access denied"));
+    }
+
+    #[test]
+    fn other_errors_are_dbus_errors() {
+        let Some(bus) = TestBus::start() else { return };
+        let _calls = mock_login1(
+            &bus.address,
+            "yes",
+            Some("org.freedesktop.login1.OperationInProgress"),
+        );
+        let err = client(&bus).sleep("Suspend").unwrap_err();
+        assert!(matches!(err, Error::DBus(dbus::Error::Remote { .. })));
+    }
+}
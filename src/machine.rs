@@ -0,0 +1,757 @@
+//! When to sleep, worked out from xscreensaver's events and the passage of time
+
+use crate::{
+    clock::{BootInstant, Elapsed},
+    event::Event,
+    watch::{ScreenState, WatchEvent},
+};
+use std::time::Duration;
+
+/// Sleeps shorter than this are just the process being descheduled
+pub const MIN_SLEEP: Duration = Duration::from_secs(1);
+
+/// Something for the caller to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Log(String),
+    /// Go to sleep, then report how it went with `SuspendStateMachine::suspended`
+    Suspend {
+        trigger: Trigger,
+        sleeps: u32,
+        locked_for: Duration,
+    },
+    /// Warn the user it's going to sleep at this time, unless they unlock first
+    Warn(BootInstant),
+    /// The warning's over without sleeping
+    Unwarn,
+    /// Call `tick` at this time, or wait for the next event if None.
+    /// Replaces any earlier wake.
+    ScheduleWake(Option<BootInstant>),
+}
+
+/// Why an `Action::Suspend` is sleeping now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// The screen has been locked for dpmsOff
+    DpmsElapsed,
+    /// Woken up or not slept, and still locked after the password timeout
+    ReSuspend,
+    /// Asked to with `suspend_now`
+    Requested,
+}
+
+impl Trigger {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Trigger::DpmsElapsed => "dpms-elapsed",
+            Trigger::ReSuspend => "re-suspend",
+            Trigger::Requested => "requested",
+        }
+    }
+}
+
+/// How an `Action::Suspend` went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Slept,
+    /// Was inhibited or vetoed. Try again once the inhibitor expires, if that's known.
+    NotSlept {
+        retry_at: Option<BootInstant>,
+    },
+    /// Tried but didn't sleep. Try again at `retry_at`, instead of after the password timeout.
+    Failed {
+        retry_at: BootInstant,
+    },
+}
+
+/// Whether the screen is locked, and if so what we're waiting for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    Unlocked,
+    /// Waiting for dpmsOff
+    Locked,
+    /// Slept or tried to, and waiting for the password timeout
+    Retrying,
+    /// Counting down the grace period before sleeping
+    Warning,
+}
+
+impl LockState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LockState::Unlocked => "unlocked",
+            LockState::Locked => "locked",
+            LockState::Retrying => "retrying",
+            LockState::Warning => "warning",
+        }
+    }
+}
+
+/// What a `SuspendStateMachine` is doing, for showing to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub state: LockState,
+    /// When the screen locked
+    pub locked_since: Option<BootInstant>,
+    /// How many times we've slept since, and for how long
+    pub sleeps: u32,
+    pub asleep: Duration,
+    /// When it will next try to sleep
+    pub next_suspend: Option<BootInstant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Unlocked,
+    /// Locked, and waiting for dpmsOff from `since` before sleeping
+    Locked {
+        since: BootInstant,
+    },
+    /// Tried to sleep, and waiting for the password timeout from `since` to try again
+    Retrying {
+        since: BootInstant,
+    },
+    /// Warned at `since` that it's going to sleep after the grace period
+    Warning {
+        since: BootInstant,
+        trigger: Trigger,
+    },
+}
+
+/// Decides when to sleep. Doesn't do anything itself or read the time, so it can be driven
+/// by any clock.
+#[derive(Debug)]
+pub struct SuspendStateMachine {
+    /// None if DPMS is disabled, and so the screen never turns off
+    dpms_off: Option<Duration>,
+    password_timeout: Duration,
+    /// How long to warn for before sleeping
+    grace_period: Duration,
+    state: State,
+    /// When the screen locked, how many times we've slept since, and for how long
+    locked_since: Option<BootInstant>,
+    sleeps: u32,
+    asleep: Duration,
+    /// When the inhibitor that stopped the last sleep expires
+    retry_at: Option<BootInstant>,
+    /// When to try again after failing to sleep
+    backoff_until: Option<BootInstant>,
+}
+
+impl SuspendStateMachine {
+    pub fn new(dpms_off: Option<Duration>, password_timeout: Duration) -> Self {
+        SuspendStateMachine {
+            dpms_off,
+            password_timeout,
+            grace_period: Duration::ZERO,
+            state: State::Unlocked,
+            locked_since: None,
+            sleeps: 0,
+            asleep: Duration::ZERO,
+            retry_at: None,
+            backoff_until: None,
+        }
+    }
+
+    /// Use new timeouts, e.g. after xscreensaver's settings changed
+    pub fn set_timeouts(
+        &mut self,
+        dpms_off: Option<Duration>,
+        password_timeout: Duration,
+        now: BootInstant,
+    ) -> Vec<Action> {
+        self.dpms_off = dpms_off;
+        self.password_timeout = password_timeout;
+        self.due(now)
+    }
+
+    /// Warn for this long before sleeping, with `Action::Warn`, rather than sleeping as soon
+    /// as it's time. Sleeping when asked to with `suspend_now` isn't warned about.
+    pub fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Something happened in xscreensaver
+    pub fn event(&mut self, event: &WatchEvent, now: BootInstant) -> Vec<Action> {
+        let mut actions = Vec::new();
+        let warning = self.warning();
+        match event {
+            // Events may have been missed, so start again from what xscreensaver reports
+            WatchEvent::Resync(state) => {
+                actions.push(Action::Log(format!(
+                    "Resyncing with xscreensaver: {state:?}"
+                )));
+                self.reset();
+                // We don't know when it locked, so count from now
+                if let Some(ScreenState::Locked) = state {
+                    self.lock(now);
+                }
+            }
+            // Keep the original lock time if xscreensaver reports it again
+            WatchEvent::Event(Event::Lock(_)) if self.state != State::Unlocked => {}
+            WatchEvent::Event(Event::Lock(_)) => self.lock(now),
+            // Unblanking only happens once the password has been entered
+            WatchEvent::Event(Event::Unblank(_)) => {
+                if let Some(since) = self.locked_since {
+                    actions.push(Action::Log(format!(
+                        "Unlocked after {}s, {}s of it asleep",
+                        now.duration_since(since).as_secs(),
+                        self.asleep.as_secs()
+                    )));
+                }
+                self.reset();
+            }
+            // Blanked without locking, switching hacks or throttling
+            // doesn't change whether we should suspend
+            WatchEvent::Event(
+                Event::Blank(_) | Event::Run { .. } | Event::Throttle(_) | Event::Unthrottle(_),
+            ) => {}
+        }
+        if warning && !self.warning() {
+            actions.push(Action::Unwarn);
+        }
+        actions.extend(self.due(now));
+        actions
+    }
+
+    /// Time has passed, as measured by a `ClockWatch`
+    pub fn tick(&mut self, now: BootInstant, elapsed: Elapsed) -> Vec<Action> {
+        let mut actions = Vec::new();
+        if elapsed.asleep >= MIN_SLEEP {
+            actions.push(Action::Log(format!(
+                "Was asleep for {}s",
+                elapsed.asleep.as_secs()
+            )));
+            // Whether we put it to sleep or something else did, give the user
+            // the password timeout to unlock before sleeping again
+            if self.state != State::Unlocked {
+                actions.push(Action::Log("Woken up but not unlocked".into()));
+                if self.warning() {
+                    actions.push(Action::Unwarn);
+                }
+                self.asleep += elapsed.asleep;
+                self.state = State::Retrying { since: now };
+            }
+        }
+        if let Some(jump) = elapsed.wall_jump {
+            actions.push(Action::Log(format!("Wall clock jumped {jump}")));
+        }
+        actions.extend(self.due(now));
+        actions
+    }
+
+    /// Report how an `Action::Suspend` went
+    pub fn suspended(&mut self, outcome: Outcome, now: BootInstant) -> Vec<Action> {
+        if self.state != State::Unlocked {
+            match outcome {
+                Outcome::Slept => self.sleeps += 1,
+                Outcome::NotSlept { retry_at } => self.retry_at = retry_at,
+                Outcome::Failed { retry_at } => self.backoff_until = Some(retry_at),
+            }
+            self.state = State::Retrying { since: now };
+        }
+        vec![Action::ScheduleWake(self.deadline())]
+    }
+
+    /// Where it's up to
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: match self.state {
+                State::Unlocked => LockState::Unlocked,
+                State::Locked { .. } => LockState::Locked,
+                State::Retrying { .. } => LockState::Retrying,
+                State::Warning { .. } => LockState::Warning,
+            },
+            locked_since: self.locked_since,
+            sleeps: self.sleeps,
+            asleep: self.asleep,
+            next_suspend: self.deadline(),
+        }
+    }
+
+    fn lock(&mut self, now: BootInstant) {
+        self.state = State::Locked { since: now };
+        self.locked_since = Some(now);
+    }
+
+    fn reset(&mut self) {
+        *self = SuspendStateMachine {
+            grace_period: self.grace_period,
+            ..SuspendStateMachine::new(self.dpms_off, self.password_timeout)
+        };
+    }
+
+    fn warning(&self) -> bool {
+        matches!(self.state, State::Warning { .. })
+    }
+
+    /// Sleep now rather than waiting, if the screen is locked
+    pub fn suspend_now(&mut self, now: BootInstant) -> Vec<Action> {
+        if self.state == State::Unlocked {
+            return Vec::new();
+        }
+        let mut actions = vec![Action::Log("Asked to sleep now".into())];
+        actions.extend(self.suspend(Trigger::Requested, now));
+        actions
+    }
+
+    /// Sleep if it's time, and say when to check again
+    fn due(&mut self, now: BootInstant) -> Vec<Action> {
+        if self.deadline().is_none_or(|deadline| now < deadline) {
+            return vec![Action::ScheduleWake(self.deadline())];
+        }
+        let (trigger, message) = match self.state {
+            State::Warning { trigger, .. } => {
+                let mut actions = vec![Action::Log("Grace period is over".into())];
+                actions.extend(self.suspend(trigger, now));
+                return actions;
+            }
+            State::Retrying { .. } => (Trigger::ReSuspend, "Locked and lock timeout has passed"),
+            _ => (Trigger::DpmsElapsed, "Locked, and dpms time has elapsed"),
+        };
+        let mut actions = vec![Action::Log(message.into())];
+        if self.grace_period.is_zero() {
+            actions.extend(self.suspend(trigger, now));
+            return actions;
+        }
+        self.state = State::Warning {
+            since: now,
+            trigger,
+        };
+        actions.push(Action::Warn(now + self.grace_period));
+        actions.push(Action::ScheduleWake(self.deadline()));
+        actions
+    }
+
+    /// Sleep, then wait for the password timeout before sleeping again
+    fn suspend(&mut self, trigger: Trigger, now: BootInstant) -> Vec<Action> {
+        let suspend = Action::Suspend {
+            trigger,
+            sleeps: self.sleeps,
+            locked_for: self
+                .locked_since
+                .map(|since| now.duration_since(since))
+                .unwrap_or_default(),
+        };
+        self.state = State::Retrying { since: now };
+        self.retry_at = None;
+        self.backoff_until = None;
+        vec![suspend, Action::ScheduleWake(self.deadline())]
+    }
+
+    /// When to sleep next, if ever
+    fn deadline(&self) -> Option<BootInstant> {
+        match self.state {
+            State::Unlocked => None,
+            State::Locked { since } => self.dpms_off.map(|dpms_off| since + dpms_off),
+            State::Retrying { .. } if self.backoff_until.is_some() => self.backoff_until,
+            State::Retrying { since } => {
+                let retry = since + self.password_timeout;
+                Some(self.retry_at.map_or(retry, |at| at.min(retry)))
+            }
+            State::Warning { since, .. } => Some(since + self.grace_period),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{test_clock::TestClock, Clock, ClockWatch};
+
+    const DPMS_OFF: Duration = Duration::from_secs(600);
+    const PASSWORD_TIMEOUT: Duration = Duration::from_secs(90);
+
+    /// A state machine driven by a clock that only moves when told to
+    struct Harness {
+        clock: TestClock,
+        watch: ClockWatch<TestClock>,
+        machine: SuspendStateMachine,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let clock = TestClock::new();
+            Harness {
+                watch: ClockWatch::new(clock.clone()),
+                clock,
+                machine: SuspendStateMachine::new(Some(DPMS_OFF), PASSWORD_TIMEOUT),
+            }
+        }
+
+        fn now(&self) -> BootInstant {
+            self.clock.now()
+        }
+
+        fn event(&mut self, line: &str) -> Vec<Action> {
+            let event = WatchEvent::Event(line.parse().unwrap());
+            self.machine.event(&event, self.now())
+        }
+
+        /// Let time pass while awake, then tick
+        fn advance(&mut self, by: Duration) -> Vec<Action> {
+            self.clock.advance(by);
+            self.machine.tick(self.clock.now(), self.watch.tick())
+        }
+
+        /// Sleep, then tick on waking up
+        fn sleep(&mut self, by: Duration) -> Vec<Action> {
+            self.clock.sleep(by);
+            self.machine.tick(self.clock.now(), self.watch.tick())
+        }
+
+        fn suspended(&mut self, outcome: Outcome) -> Vec<Action> {
+            self.machine.suspended(outcome, self.now())
+        }
+    }
+
+    const LOCK: &str = "LOCK Fri Nov 13 17:39:59 2020";
+    const BLANK: &str = "BLANK Fri Nov 13 17:39:59 2020";
+    const UNBLANK: &str = "UNBLANK Fri Nov 13 17:39:59 2020";
+
+    fn suspends(actions: &[Action]) -> Vec<(u32, Duration)> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Suspend {
+                    sleeps, locked_for, ..
+                } => Some((*sleeps, *locked_for)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn triggers(actions: &[Action]) -> Vec<Trigger> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Suspend { trigger, .. } => Some(*trigger),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn wake(actions: &[Action]) -> Option<BootInstant> {
+        match actions.last() {
+            Some(Action::ScheduleWake(at)) => *at,
+            other => panic!("expected a ScheduleWake last, got {other:?}"),
+        }
+    }
+
+    fn logs(actions: &[Action]) -> Vec<&str> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Log(message) => Some(message.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unlocked_never_wakes() {
+        let mut h = Harness::new();
+        assert_eq!(
+            h.machine.event(&WatchEvent::Resync(None), h.now()).last(),
+            Some(&Action::ScheduleWake(None))
+        );
+        let actions = h.advance(Duration::from_secs(24 * 3600));
+        assert_eq!(actions, vec![Action::ScheduleWake(None)]);
+    }
+
+    #[test]
+    fn suspends_at_dpms_off_after_locking() {
+        let mut h = Harness::new();
+        let locked = h.now();
+        assert_eq!(wake(&h.event(LOCK)), Some(locked + DPMS_OFF));
+
+        let actions = h.advance(DPMS_OFF - Duration::from_secs(1));
+        assert_eq!(suspends(&actions), vec![]);
+        assert_eq!(wake(&actions), Some(locked + DPMS_OFF));
+
+        let actions = h.advance(Duration::from_secs(1));
+        assert_eq!(suspends(&actions), vec![(0, DPMS_OFF)]);
+        assert_eq!(triggers(&actions), vec![Trigger::DpmsElapsed]);
+        assert_eq!(logs(&actions), vec!["Locked, and dpms time has elapsed"]);
+    }
+
+    #[test]
+    fn snapshot_shows_what_its_waiting_for() {
+        let mut h = Harness::new();
+        assert_eq!(h.machine.snapshot().state, LockState::Unlocked);
+        let locked = h.now();
+        h.event(LOCK);
+        assert_eq!(
+            h.machine.snapshot(),
+            Snapshot {
+                state: LockState::Locked,
+                locked_since: Some(locked),
+                sleeps: 0,
+                asleep: Duration::ZERO,
+                next_suspend: Some(locked + DPMS_OFF),
+            }
+        );
+        h.advance(DPMS_OFF);
+        h.suspended(Outcome::Slept);
+        h.sleep(Duration::from_secs(60));
+        let snapshot = h.machine.snapshot();
+        assert_eq!(snapshot.state, LockState::Retrying);
+        assert_eq!((snapshot.sleeps, snapshot.asleep.as_secs()), (1, 60));
+        assert_eq!(snapshot.next_suspend, Some(h.now() + PASSWORD_TIMEOUT));
+    }
+
+    #[test]
+    fn suspends_now_only_when_locked() {
+        let mut h = Harness::new();
+        assert_eq!(h.machine.suspend_now(h.now()), vec![]);
+        h.event(LOCK);
+        h.advance(Duration::from_secs(60));
+        let actions = h.machine.suspend_now(h.now());
+        assert_eq!(logs(&actions), vec!["Asked to sleep now"]);
+        assert_eq!(suspends(&actions), vec![(0, Duration::from_secs(60))]);
+        assert_eq!(triggers(&actions), vec![Trigger::Requested]);
+        assert_eq!(wake(&actions), Some(h.now() + PASSWORD_TIMEOUT));
+    }
+
+    #[test]
+    fn warns_for_the_grace_period_before_sleeping() {
+        let mut h = Harness::new();
+        h.machine.set_grace_period(Duration::from_secs(60));
+        h.event(LOCK);
+        let actions = h.advance(DPMS_OFF);
+        assert_eq!(suspends(&actions), vec![]);
+        assert!(actions.contains(&Action::Warn(h.now() + Duration::from_secs(60))));
+        assert_eq!(wake(&actions), Some(h.now() + Duration::from_secs(60)));
+        assert_eq!(h.machine.snapshot().state, LockState::Warning);
+
+        let actions = h.advance(Duration::from_secs(60));
+        assert_eq!(logs(&actions), vec!["Grace period is over"]);
+        assert_eq!(
+            suspends(&actions),
+            vec![(0, DPMS_OFF + Duration::from_secs(60))]
+        );
+        assert_eq!(triggers(&actions), vec![Trigger::DpmsElapsed]);
+
+        // And again after waking up still locked
+        h.suspended(Outcome::Slept);
+        h.sleep(Duration::from_secs(3600));
+        let actions = h.advance(PASSWORD_TIMEOUT);
+        assert_eq!(suspends(&actions), vec![]);
+        let actions = h.advance(Duration::from_secs(60));
+        assert_eq!(triggers(&actions), vec![Trigger::ReSuspend]);
+    }
+
+    #[test]
+    fn unlocking_during_the_grace_period_cancels_it() {
+        let mut h = Harness::new();
+        h.machine.set_grace_period(Duration::from_secs(60));
+        h.event(LOCK);
+        h.advance(DPMS_OFF);
+        let actions = h.event(UNBLANK);
+        assert!(actions.contains(&Action::Unwarn));
+        assert_eq!(wake(&actions), None);
+        assert_eq!(suspends(&h.advance(Duration::from_secs(60))), vec![]);
+
+        // Asking to sleep doesn't wait
+        h.event(LOCK);
+        assert_eq!(suspends(&h.machine.suspend_now(h.now())).len(), 1);
+    }
+
+    #[test]
+    fn unlocking_cancels_the_sleep() {
+        let mut h = Harness::new();
+        h.event(LOCK);
+        h.advance(Duration::from_secs(60));
+        let actions = h.event(UNBLANK);
+        assert_eq!(logs(&actions), vec!["Unlocked after 60s, 0s of it asleep"]);
+        assert_eq!(wake(&actions), None);
+        assert_eq!(suspends(&h.advance(DPMS_OFF)), vec![]);
+    }
+
+    #[test]
+    fn blanking_without_locking_never_suspends() {
+        let mut h = Harness::new();
+        assert_eq!(wake(&h.event(BLANK)), None);
+        assert_eq!(suspends(&h.advance(DPMS_OFF * 2)), vec![]);
+        let actions = h.event(UNBLANK);
+        assert_eq!(logs(&actions), Vec::<&str>::new());
+        assert_eq!(wake(&actions), None);
+    }
+
+    #[test]
+    fn locking_again_keeps_the_original_time() {
+        let mut h = Harness::new();
+        let locked = h.now();
+        h.event(LOCK);
+        h.advance(Duration::from_secs(300));
+        assert_eq!(wake(&h.event(LOCK)), Some(locked + DPMS_OFF));
+        assert_eq!(
+            suspends(&h.advance(Duration::from_secs(300))),
+            vec![(0, DPMS_OFF)]
+        );
+    }
+
+    #[test]
+    fn sleeps_again_when_woken_but_not_unlocked() {
+        let mut h = Harness::new();
+        h.event(LOCK);
+        h.advance(DPMS_OFF);
+        h.suspended(Outcome::Slept);
+
+        let actions = h.sleep(Duration::from_secs(3600));
+        assert_eq!(
+            logs(&actions),
+            vec!["Was asleep for 3600s", "Woken up but not unlocked"]
+        );
+        assert_eq!(suspends(&actions), vec![]);
+        assert_eq!(wake(&actions), Some(h.now() + PASSWORD_TIMEOUT));
+
+        let actions = h.advance(PASSWORD_TIMEOUT);
+        assert_eq!(logs(&actions), vec!["Locked and lock timeout has passed"]);
+        assert_eq!(triggers(&actions), vec![Trigger::ReSuspend]);
+        // Locked time includes time asleep
+        assert_eq!(
+            suspends(&actions),
+            vec![(1, DPMS_OFF + Duration::from_secs(3600) + PASSWORD_TIMEOUT)]
+        );
+        h.suspended(Outcome::Slept);
+        h.sleep(Duration::from_secs(60));
+        assert_eq!(
+            logs(&h.event(UNBLANK)),
+            vec!["Unlocked after 4350s, 3660s of it asleep"]
+        );
+    }
+
+    #[test]
+    fn waits_for_the_password_timeout_after_sleeping_while_locked() {
+        // Something else put it to sleep, e.g. closing the lid
+        let mut h = Harness::new();
+        h.event(LOCK);
+        h.advance(Duration::from_secs(60));
+        let actions = h.sleep(DPMS_OFF);
+        assert_eq!(suspends(&actions), vec![]);
+        assert_eq!(wake(&actions), Some(h.now() + PASSWORD_TIMEOUT));
+        assert_eq!(suspends(&h.advance(PASSWORD_TIMEOUT)).len(), 1);
+    }
+
+    #[test]
+    fn sleeping_while_unlocked_changes_nothing() {
+        let mut h = Harness::new();
+        let actions = h.sleep(Duration::from_secs(3600));
+        assert_eq!(logs(&actions), vec!["Was asleep for 3600s"]);
+        assert_eq!(wake(&actions), None);
+    }
+
+    #[test]
+    fn retries_after_the_password_timeout_when_not_slept() {
+        let mut h = Harness::new();
+        h.event(LOCK);
+        h.advance(DPMS_OFF);
+        let actions = h.suspended(Outcome::NotSlept { retry_at: None });
+        assert_eq!(wake(&actions), Some(h.now() + PASSWORD_TIMEOUT));
+        assert_eq!(
+            suspends(&h.advance(PASSWORD_TIMEOUT)),
+            vec![(0, DPMS_OFF + PASSWORD_TIMEOUT)]
+        );
+    }
+
+    #[test]
+    fn retries_when_the_inhibitor_expires() {
+        let mut h = Harness::new();
+        h.event(LOCK);
+        h.advance(DPMS_OFF);
+        let expires = h.now() + Duration::from_secs(10);
+        let actions = h.suspended(Outcome::NotSlept {
+            retry_at: Some(expires),
+        });
+        assert_eq!(wake(&actions), Some(expires));
+        assert_eq!(suspends(&h.advance(Duration::from_secs(10))).len(), 1);
+
+        // An inhibitor lasting longer than the password timeout doesn't delay retrying
+        h.suspended(Outcome::NotSlept {
+            retry_at: Some(h.now() + Duration::from_secs(3600)),
+        });
+        assert_eq!(suspends(&h.advance(PASSWORD_TIMEOUT)).len(), 1);
+    }
+
+    #[test]
+    fn backs_off_after_failing_to_sleep() {
+        let mut h = Harness::new();
+        h.event(LOCK);
+        h.advance(DPMS_OFF);
+        let retry_at = h.now() + PASSWORD_TIMEOUT * 2;
+        let actions = h.suspended(Outcome::Failed { retry_at });
+        assert_eq!(wake(&actions), Some(retry_at));
+        assert_eq!(suspends(&h.advance(PASSWORD_TIMEOUT)), vec![]);
+        let actions = h.advance(PASSWORD_TIMEOUT);
+        assert_eq!(triggers(&actions), vec![Trigger::ReSuspend]);
+        // Only the once
+        assert_eq!(wake(&actions), Some(h.now() + PASSWORD_TIMEOUT));
+    }
+
+    #[test]
+    fn resync_while_locked_counts_from_now() {
+        let mut h = Harness::new();
+        h.event(LOCK);
+        h.advance(DPMS_OFF - Duration::from_secs(1));
+        let resynced = h.now();
+        let actions = h
+            .machine
+            .event(&WatchEvent::Resync(Some(ScreenState::Locked)), resynced);
+        assert_eq!(wake(&actions), Some(resynced + DPMS_OFF));
+        assert_eq!(suspends(&h.advance(Duration::from_secs(1))), vec![]);
+    }
+
+    #[test]
+    fn resync_while_unlocked_forgets_the_lock() {
+        let mut h = Harness::new();
+        h.event(LOCK);
+        h.advance(DPMS_OFF);
+        h.suspended(Outcome::Slept);
+        let actions = h
+            .machine
+            .event(&WatchEvent::Resync(Some(ScreenState::Unblanked)), h.now());
+        assert_eq!(wake(&actions), None);
+        assert_eq!(suspends(&h.advance(PASSWORD_TIMEOUT)), vec![]);
+    }
+
+    #[test]
+    fn never_suspends_without_dpms() {
+        let mut h = Harness::new();
+        h.machine.set_timeouts(None, PASSWORD_TIMEOUT, h.now());
+        assert_eq!(wake(&h.event(LOCK)), None);
+        assert_eq!(suspends(&h.advance(DPMS_OFF)), vec![]);
+
+        // Turning it back on counts from when it locked
+        let actions = h
+            .machine
+            .set_timeouts(Some(DPMS_OFF), PASSWORD_TIMEOUT, h.now());
+        assert_eq!(suspends(&actions), vec![(0, DPMS_OFF)]);
+    }
+
+    #[test]
+    fn huge_timeouts_are_never_reached() {
+        let mut h = Harness::new();
+        h.machine
+            .set_timeouts(Some(Duration::MAX), Duration::MAX, h.now());
+        h.machine.set_grace_period(Duration::MAX);
+        assert_eq!(wake(&h.event(LOCK)), Some(h.now() + Duration::MAX));
+        assert_eq!(
+            suspends(&h.advance(Duration::from_secs(365 * 24 * 3600))),
+            vec![]
+        );
+
+        let actions = h.machine.suspend_now(h.now());
+        assert_eq!(wake(&actions), Some(h.now() + Duration::MAX));
+        h.suspended(Outcome::Slept);
+        assert_eq!(suspends(&h.sleep(Duration::from_secs(60))), vec![]);
+    }
+
+    #[test]
+    fn logs_wall_clock_jumps() {
+        let mut h = Harness::new();
+        h.clock.set_wall(h.clock.wall() + Duration::from_secs(3600));
+        let actions = h.advance(Duration::ZERO);
+        assert_eq!(logs(&actions), vec!["Wall clock jumped forward 3600s"]);
+    }
+}
@@ -1,174 +1,207 @@
+mod cli;
+
+use cli::{Args, Command, USAGE};
 use std::{
-    fs::metadata,
-    io::{BufRead, BufReader},
-    process::{Command, Stdio},
-    sync::mpsc::{self, Receiver},
-    thread,
-    time::{Duration, SystemTime},
+    os::unix::process::ExitStatusExt,
+    process::{self, ExitCode},
+    time::SystemTime,
+};
+use xscreensaver_suspend::{
+    config::{format_duration, Config},
+    control::{self, Client, Request},
+    daemon, home_dir,
+    inhibit::{self, logind_inhibitors, Inhibit, InhibitDir, Process},
+    status::Status,
+    Error, XscreensaverSettings, NOT_RUNNING,
 };
 
-/// How long is .no_suspend valid for
-const NO_SUSPEND: u64 = 8 * 60 * 60;
-/// How many multiples of password_time before suspending again
-static PASSWORD_TIMEOUT: u32 = 3;
-/// How long to wait for xscreensaver events
-static SLEEP: u64 = 5;
-
-fn main() -> ! {
-    let settings = XscreensaverSettings::load();
-    if !settings.dpms_enabled {
-        eprintln!("dpmsOff not configured in ~/.xscreensaver");
-        std::process::exit(1);
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(e @ Error::Usage(_)) => {
+            eprintln!("{e}\n\n{USAGE}");
+            ExitCode::from(e.exit_code())
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(e.exit_code())
+        }
     }
+}
 
-    let rx = spawn_xscreensaver_watch();
-
-    let mut timer = None;
-    let mut locked = None;
-    let password_timeout = settings.password_timeout * PASSWORD_TIMEOUT;
-    loop {
-        if let Ok(status) = rx.recv_timeout(Duration::from_secs(SLEEP)) {
-            match status {
-                _ if status.contains("LOCK") => timer = Some(std::time::Instant::now()),
-                // Maybe other events should be processed?
-                _ => {
-                    locked = None;
-                    timer = None;
-                }
+fn run() -> Result<ExitCode, Error> {
+    let args = Args::parse(std::env::args().skip(1)).map_err(Error::Usage)?;
+    if args.help {
+        print!("{USAGE}");
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut config = Config::load(args.config.as_deref())?;
+    args.apply(&mut config)?;
+    match args.command {
+        Command::Run => run_daemon(config).map(|()| ExitCode::SUCCESS),
+        Command::Inhibit {
+            duration,
+            pid,
+            command,
+            reason,
+        } => {
+            let until = duration
+                .map(|duration| {
+                    SystemTime::now().checked_add(duration).ok_or_else(|| {
+                        Error::Usage(format!("--for {} is too long", format_duration(duration)))
+                    })
+                })
+                .transpose()?;
+            if !command.is_empty() {
+                return inhibit_while_running(until, &command, reason);
             }
+            let process = pid
+                .map(|pid| Process::of(pid).ok_or(inhibit::Error::NoProcess(pid)))
+                .transpose()?;
+            let reason = match &process {
+                Some(process) if reason.is_empty() => {
+                    let name = process.name().unwrap_or_default();
+                    format!("{name} (pid {})", process.pid)
+                }
+                _ => reason,
+            };
+            let inhibit = Inhibit {
+                id: String::new(),
+                until,
+                process,
+                reason,
+            };
+            println!("{}", add_inhibit(&inhibit)?);
+            Ok(ExitCode::SUCCESS)
         }
-        match (timer, locked) {
-            // Locked, and dpmsOff time has elapsed
-            (Some(time), None) if time.elapsed() > settings.dpms_off => {
-                println!("Locked, and dpms time has elapsed");
-                timer = None;
-                locked = Some(());
-                suspend();
-            }
-            // Locked and password_timeout has passed
-            (Some(time), Some(_)) if time.elapsed() > password_timeout => {
-                println!("Locked and lock timeout has passed");
-                timer = None;
-                locked = Some(());
-                suspend();
-            }
-            // Woken up but not unlocked
-            (None, Some(_)) => {
-                println!("Woken up but not unlocked");
-                timer = Some(std::time::Instant::now());
+        Command::Uninhibit(ids) => {
+            uninhibit(&ids)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::List => {
+            list(&config);
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Status { json } => {
+            let daemon = match Client::for_user().and_then(|client| client.status()) {
+                Ok(status) => Some(status),
+                Err(control::Error::NotRunning | control::Error::NoRuntimeDir) => None,
+                Err(e) => return Err(e.into()),
+            };
+            let status = Status {
+                daemon,
+                inhibits: inhibit::active(config.no_suspend),
+                logind: logind_inhibitors(&config.action).unwrap_or_else(|e| {
+                    eprintln!("Couldn't check logind inhibitors: {e}");
+                    Vec::new()
+                }),
+            };
+            match json {
+                true => println!("{}", status.to_json()),
+                false => print!("{status}"),
             }
-            (_, _) => {}
-        };
+            // So scripts can tell
+            Ok(match status.daemon {
+                Some(_) => ExitCode::SUCCESS,
+                None => ExitCode::from(NOT_RUNNING),
+            })
+        }
+        Command::SuspendNow => ask(Request::SuspendNow),
+        Command::Reload => ask(Request::Reload),
+        Command::Pause => ask(Request::Pause),
+        Command::Resume => ask(Request::Resume),
     }
 }
 
-/// Suspend the system
-fn suspend() {
-    if inhibit_suspend() {
-        return;
-    }
-    let _ = Command::new("/usr/bin/systemctl")
-        .arg("suspend")
-        .spawn()
-        .expect("Suspending");
+/// Send a request that only the running daemon can answer
+fn ask(request: Request) -> Result<ExitCode, Error> {
+    Client::for_user()?.send(&request)?;
+    Ok(ExitCode::SUCCESS)
 }
 
-/// Don't suspend if a '.no_suspend file was modified in the last 8 hours
-/// `touch ~/.no_suspend` to block suspend
-fn inhibit_suspend() -> bool {
-    let filename = format!(
-        "{}/.no_suspend",
-        std::env::var("HOME").expect("Get HOME environment variable")
-    );
-    let no_suspend_lifetime = SystemTime::now()
-        .checked_sub(Duration::from_secs(NO_SUSPEND))
-        .expect("Time subtraction");
-
-    metadata(filename)
-        .and_then(|m| m.modified())
-        .map(|modified| modified >= no_suspend_lifetime)
-        .unwrap_or_default()
+/// Have the daemon add an inhibit, or add it here if it isn't running. Returns its ID.
+fn add_inhibit(inhibit: &Inhibit) -> Result<String, Error> {
+    match Client::for_user().and_then(|client| client.inhibit(inhibit)) {
+        Err(control::Error::NotRunning | control::Error::NoRuntimeDir) => {
+            let dir = InhibitDir::for_user()?;
+            Ok(dir
+                .add(inhibit.until, inhibit.process.clone(), &inhibit.reason)?
+                .id)
+        }
+        added => Ok(added?),
+    }
 }
 
-/// Watch Xscreensaver output for events
-fn spawn_xscreensaver_watch() -> Receiver<String> {
-    let mut xs = Command::new("/usr/bin/xscreensaver-command")
-        .arg("-watch")
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Running xscreensaver");
-
-    let stdout = xs.stdout.take().expect("Opening stdout");
-    let mut lines = BufReader::new(stdout).lines();
-    let (tx, rx) = mpsc::channel::<String>();
-    thread::spawn(move || {
-        while let Some(Ok(line)) = lines.next() {
-            tx.send(line).unwrap();
+/// Have the daemon remove inhibits, or all of our own if there are no IDs, or remove them
+/// here if it isn't running
+fn uninhibit(ids: &[String]) -> Result<(), Error> {
+    let request = Request::Uninhibit(ids.to_vec());
+    match Client::for_user().and_then(|client| client.send(&request)) {
+        Err(control::Error::NotRunning | control::Error::NoRuntimeDir) => {
+            Ok(InhibitDir::for_user()?.uninhibit(ids)?)
         }
-    });
-    rx
+        removed => removed.map(drop).map_err(Error::from),
+    }
 }
 
-/// Settings from ~/.xscreensaver
-#[derive(Default, Debug)]
-struct XscreensaverSettings {
-    /// Is DPMS enabled
-    dpms_enabled: bool,
-    /// How long until DPMS activates
-    dpms_off: Duration,
-    /// How long should a password dialog box be left on the screen
-    password_timeout: Duration,
+fn run_daemon(config: Config) -> Result<(), Error> {
+    config.validate()?;
+    home_dir().ok_or(Error::NoHome)?;
+    let settings = XscreensaverSettings::load(&config.xrdb)?;
+    if !settings.dpms_enabled {
+        return Err(Error::DpmsDisabled);
+    }
+
+    match daemon::run(config, settings)? {}
 }
 
-impl XscreensaverSettings {
-    fn load() -> Self {
-        let filename = format!(
-            "{}/.xscreensaver",
-            std::env::var("HOME").expect("Get HOME environment variable")
-        );
-        let config = std::fs::read_to_string(filename).expect("Read XScreensaver config");
-        let mut settings = Self::default();
-        for line in config.lines() {
-            match line {
-                value if line.contains("dpmsEnabled") => {
-                    settings.dpms_enabled = XscreensaverSettings::parse_bool(value)
-                }
-                value if line.contains("dpmsOff") => {
-                    settings.dpms_off = XscreensaverSettings::parse_time(value)
-                }
-                value if line.contains("passwdTimeout") => {
-                    settings.password_timeout = XscreensaverSettings::parse_time(value)
-                }
-                _ => {}
-            };
-        }
-        settings
+/// Run a command, inhibiting sleep until it exits, and exit the way it did.
+/// The inhibit is tied to this process, so it stops even if this is killed.
+fn inhibit_while_running(
+    until: Option<SystemTime>,
+    command: &[String],
+    reason: String,
+) -> Result<ExitCode, Error> {
+    let reason = match reason.is_empty() {
+        true => command.join(" "),
+        false => reason,
+    };
+    let id = add_inhibit(&Inhibit {
+        id: String::new(),
+        until,
+        process: Process::of(process::id()),
+        reason,
+    })?;
+    let status = process::Command::new(&command[0])
+        .args(&command[1..])
+        .status()
+        .map_err(|e| Error::Spawn(command[0].clone(), e));
+    if let Err(e) = uninhibit(&[id]) {
+        eprintln!("Couldn't stop inhibiting: {e}");
     }
+    let status = status?;
+    // Like a shell, 128 plus the signal if it was killed
+    let code = status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1);
+    Ok(ExitCode::from(code as u8))
+}
 
-    /// Parse a bool from the config
-    fn parse_bool(line: &str) -> bool {
-        line.split(':')
-            .last()
-            .map(|s| s.trim().to_lowercase().parse().expect("parsing bool"))
-            .expect("Get Parsing bool")
+/// Print everything that would stop sleep now
+fn list(config: &Config) {
+    for inhibit in inhibit::active(config.no_suspend) {
+        println!("{inhibit}");
     }
-
-    /// Parse a time to a Duration
-    fn parse_time(line: &str) -> Duration {
-        let time_in_secs = line
-            .splitn(2, ':')
-            .skip(1)
-            .map(|s| {
-                s.rsplit(':')
-                    .enumerate()
-                    .map(|(i, n)| {
-                        let unit = [1, 60, 60 * 60][i];
-                        n.trim().parse::<u64>().expect("Parse time as u64") * unit
-                    })
-                    .sum()
-            })
-            .next()
-            .expect("Get time");
-        Duration::from_secs(time_in_secs)
+    match logind_inhibitors(&config.action) {
+        Ok(inhibitors) => {
+            for inhibitor in inhibitors {
+                println!(
+                    "logind: {} (pid {}): {}",
+                    inhibitor.who, inhibitor.pid, inhibitor.why
+                );
+            }
+        }
+        Err(e) => eprintln!("Couldn't check logind inhibitors: {e}"),
     }
 }
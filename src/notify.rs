@@ -0,0 +1,233 @@
+//! Desktop notifications through `org.freedesktop.Notifications`, warning that it's going to
+//! sleep, with a button to cancel.
+
+use crate::dbus::{self, Connection, Message, MessageKind, Value, Writer};
+use std::{
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+const DESTINATION: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+const INTERFACE: &str = DESTINATION;
+/// The Cancel button's action key
+const CANCEL: &str = "cancel";
+
+/// Shows the warning, and hears when it's cancelled.
+///
+/// Calls are sent without waiting for their replies, which the listening thread picks up,
+/// so the main loop never waits on the notification server.
+pub struct Notifier {
+    writer: Writer,
+    shown: Arc<Mutex<Shown>>,
+}
+
+/// What's on screen
+#[derive(Debug, Default)]
+struct Shown {
+    /// The Notify call waiting for its reply
+    pending: Option<u32>,
+    /// The notification's ID, once it's known
+    id: Option<u32>,
+    /// Close it as soon as its ID is known
+    close: bool,
+}
+
+impl Notifier {
+    /// Listen on `conn` for the notification's Cancel button, calling `cancelled` when it's
+    /// pressed
+    pub fn spawn<F>(mut conn: Connection, cancelled: F) -> Result<Self, dbus::Error>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let rule = format!("type='signal',interface='{INTERFACE}',member='ActionInvoked'");
+        conn.bus_call("AddMatch", vec![Value::String(rule)])?;
+        let notifier = Notifier {
+            writer: conn.writer(),
+            shown: Arc::new(Mutex::new(Shown::default())),
+        };
+        let (writer, shown) = (notifier.writer.clone(), notifier.shown.clone());
+        thread::spawn(move || loop {
+            let message = match conn.recv(None) {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("Lost the session bus: {e}");
+                    return;
+                }
+            };
+            let mut shown = lock(&shown);
+            match message.kind {
+                MessageKind::MethodReturn | MessageKind::Error
+                    if message.reply_serial.is_some() && message.reply_serial == shown.pending =>
+                {
+                    shown.pending = None;
+                    if message.kind == MessageKind::Error {
+                        let why = message.body.first().and_then(Value::as_str);
+                        eprintln!("Couldn't show a notification: {}", why.unwrap_or_default());
+                        continue;
+                    }
+                    let id = message.body.first().and_then(Value::as_u32);
+                    match (shown.close, id) {
+                        (true, Some(id)) => close(&writer, id),
+                        _ => shown.id = id,
+                    }
+                }
+                MessageKind::Signal if message.member.as_deref() == Some("ActionInvoked") => {
+                    match message.body.as_slice() {
+                        [Value::UInt32(id), Value::String(key)]
+                            if Some(*id) == shown.id && key == CANCEL =>
+                        {
+                            shown.id = None;
+                            drop(shown);
+                            cancelled();
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        });
+        Ok(notifier)
+    }
+
+    /// Show a notification with a Cancel button for `timeout`, replacing the last one
+    pub fn show(&self, summary: &str, body: &str, timeout: Duration) {
+        let mut shown = lock(&self.shown);
+        let actions = vec![Value::String(CANCEL.into()), Value::String("Cancel".into())];
+        let hints = vec![Value::DictEntry(
+            Box::new(Value::String("category".into())),
+            Box::new(Value::Variant(Box::new(Value::String("device".into())))),
+        )];
+        let call = Message::method_call(
+            DESTINATION,
+            PATH,
+            INTERFACE,
+            "Notify",
+            vec![
+                Value::String("xscreensaver-suspend".into()),
+                Value::UInt32(shown.id.unwrap_or(0)),
+                Value::String(String::new()),
+                Value::String(summary.into()),
+                Value::String(body.into()),
+                Value::Array("s".into(), actions),
+                Value::Array("{sv}".into(), hints),
+                Value::Int32(timeout.as_millis().try_into().unwrap_or(i32::MAX)),
+            ],
+        );
+        match self.writer.send(call) {
+            Ok(serial) => {
+                shown.pending = Some(serial);
+                shown.close = false;
+            }
+            Err(e) => eprintln!("Couldn't show a notification: {e}"),
+        }
+    }
+
+    /// Take the notification down, if it's up
+    pub fn close(&self) {
+        let mut shown = lock(&self.shown);
+        shown.close = shown.pending.is_some();
+        if let Some(id) = shown.id.take() {
+            close(&self.writer, id);
+        }
+    }
+}
+
+fn close(writer: &Writer, id: u32) {
+    let call = Message::method_call(
+        DESTINATION,
+        PATH,
+        INTERFACE,
+        "CloseNotification",
+        vec![Value::UInt32(id)],
+    );
+    if let Err(e) = writer.send(call) {
+        eprintln!("Couldn't close the notification: {e}");
+    }
+}
+
+fn lock(shown: &Mutex<Shown>) -> MutexGuard<'_, Shown> {
+    shown.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dbus::test_bus::TestBus;
+    use std::sync::mpsc;
+
+    /// Serve a fake notification server, reporting the calls made to it. Every notification
+    /// gets ID 7, and sending to the returned channel presses its Cancel button.
+    fn mock_notifications(address: &str) -> (mpsc::Receiver<Message>, mpsc::Sender<()>) {
+        let mut conn = Connection::open(address).unwrap();
+        conn.request_name(DESTINATION).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let (press, pressed) = mpsc::channel::<()>();
+        let writer = conn.writer();
+        thread::spawn(move || {
+            for () in pressed {
+                let body = vec![Value::UInt32(7), Value::String(CANCEL.into())];
+                let signal = Message::signal(PATH, INTERFACE, "ActionInvoked", body);
+                writer.send(signal).unwrap();
+            }
+        });
+        thread::spawn(move || {
+            while let Ok(Some(call)) = conn.recv(None) {
+                if call.kind != MessageKind::MethodCall || call.path.as_deref() != Some(PATH) {
+                    continue;
+                }
+                let body = match call.member.as_deref() {
+                    Some("Notify") => vec![Value::UInt32(7)],
+                    _ => vec![],
+                };
+                conn.send(Message::method_return(&call, body)).unwrap();
+                let _ = tx.send(call);
+            }
+        });
+        (rx, press)
+    }
+
+    #[test]
+    fn shows_closes_and_cancels() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let (calls, press) = mock_notifications(&bus.address);
+        let (cancel, cancelled) = mpsc::channel();
+        let notifier = Notifier::spawn(Connection::open(&bus.address).unwrap(), move || {
+            cancel.send(()).unwrap()
+        })
+        .unwrap();
+        let timeout = Duration::from_secs(5);
+
+        notifier.show("Going to suspend in 60s", "Unlock to stay awake", timeout);
+        let call = calls.recv_timeout(timeout).unwrap();
+        assert_eq!(call.member.as_deref(), Some("Notify"));
+        assert_eq!(call.signature(), "susssasa{sv}i");
+        assert_eq!(
+            call.body[3],
+            Value::String("Going to suspend in 60s".into())
+        );
+        assert_eq!(call.body[7], Value::Int32(5000));
+        // Closed as soon as its ID is known, even if that's after being asked to
+        notifier.close();
+        let call = calls.recv_timeout(timeout).unwrap();
+        assert_eq!(call.member.as_deref(), Some("CloseNotification"));
+        assert_eq!(call.body, vec![Value::UInt32(7)]);
+
+        // Pressing Cancel on a closed notification does nothing
+        press.send(()).unwrap();
+        assert!(cancelled.recv_timeout(Duration::from_millis(200)).is_err());
+
+        notifier.show("Going to suspend in 60s", "Unlock to stay awake", timeout);
+        calls.recv_timeout(timeout).unwrap();
+        // Wait for the reply to be handled
+        while lock(&notifier.shown).id.is_none() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        press.send(()).unwrap();
+        cancelled.recv_timeout(timeout).unwrap();
+    }
+}
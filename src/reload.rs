@@ -0,0 +1,139 @@
+//! Reload xscreensaver's settings when ~/.xscreensaver changes, or on SIGHUP
+
+use crate::{
+    settings::{self, Source, XscreensaverSettings},
+    sys::{Inotify, Signals, IN_CLOSE_WRITE, IN_MOVED_TO, SIGHUP},
+};
+use std::{path::PathBuf, sync::mpsc::Sender, thread};
+
+/// Send freshly loaded settings whenever they change. Settings that fail to load
+/// are logged and not sent, so the old ones stay in use.
+///
+/// Blocks SIGHUP for the calling thread, so call this before starting other threads.
+pub fn spawn_reloader<T>(xrdb: PathBuf, tx: Sender<T>)
+where
+    T: From<XscreensaverSettings> + Send + 'static,
+{
+    let sources = Source::all(&xrdb);
+    match Signals::block(&[SIGHUP]) {
+        Ok(signals) => {
+            let (sources, tx) = (sources.clone(), tx.clone());
+            thread::spawn(move || {
+                while signals.wait().is_ok() {
+                    if !reload("SIGHUP", &sources, &tx) {
+                        return;
+                    }
+                }
+            });
+        }
+        Err(e) => eprintln!("Can't handle SIGHUP: {e}"),
+    }
+
+    if let Some(file) = settings::user_file() {
+        watch_file(file, sources, tx);
+    }
+}
+
+/// Reload the settings from `sources` whenever `file` is written or replaced
+fn watch_file<T>(file: PathBuf, sources: Vec<Source>, tx: Sender<T>)
+where
+    T: From<XscreensaverSettings> + Send + 'static,
+{
+    // xscreensaver-settings replaces ~/.xscreensaver by renaming a new file over it,
+    // which a watch on the file itself would miss, so watch its directory instead
+    let (Some(dir), Some(name)) = (file.parent(), file.file_name()) else {
+        return;
+    };
+    let mut inotify = match Inotify::new().and_then(|inotify| {
+        inotify
+            .add_watch(dir, IN_CLOSE_WRITE | IN_MOVED_TO)
+            .map(|_| inotify)
+    }) {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            eprintln!("Can't watch {} for changes: {e}", file.display());
+            return;
+        }
+    };
+    let name = name.to_os_string();
+    thread::spawn(move || loop {
+        match inotify.read_names() {
+            Ok(names) if names.contains(&name) => {
+                let why = format!("{} changed", file.display());
+                if !reload(&why, &sources, &tx) {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("Watching {}: {e}", file.display());
+                return;
+            }
+        }
+    });
+}
+
+/// Load the settings and send them. Returns false once the receiver has gone away.
+fn reload<T: From<XscreensaverSettings>>(why: &str, sources: &[Source], tx: &Sender<T>) -> bool {
+    println!("Reloading xscreensaver settings: {why}");
+    match XscreensaverSettings::load_from(sources) {
+        Ok(settings) => tx.send(settings.into()).is_ok(),
+        Err(e) => {
+            eprintln!("Keeping the old settings: {e}");
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+    use std::{fs, sync::mpsc, time::Duration};
+
+    #[test]
+    fn reloads_when_the_file_changes() {
+        let dir = TempDir::new("reload");
+        let file = dir.path().join(".xscreensaver");
+        fs::write(&file, "lock: False\n").unwrap();
+        let (tx, rx) = mpsc::channel::<XscreensaverSettings>();
+        watch_file(file.clone(), vec![Source::File(file.clone())], tx);
+        let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        fs::write(&file, "lock: True\n").unwrap();
+        assert!(next().lock);
+
+        // Like xscreensaver-settings, renaming a new file over it
+        let new = dir.path().join(".xscreensaver.new");
+        fs::write(&new, "lock: False\n").unwrap();
+        fs::rename(&new, &file).unwrap();
+        assert!(!next().lock);
+
+        // Other files are ignored
+        fs::write(dir.path().join("other"), "lock: True\n").unwrap();
+        fs::write(&file, "lock: True\ntimeout: 5\n").unwrap();
+        let settings = next();
+        assert_eq!(settings.timeout, Duration::from_secs(5 * 60));
+    }
+
+    #[test]
+    fn keeps_the_old_settings_when_the_new_ones_are_bad() {
+        let dir = TempDir::new("reload");
+        let file = dir.path().join(".xscreensaver");
+        let sources = [Source::File(file.clone())];
+        let (tx, rx) = mpsc::channel::<XscreensaverSettings>();
+
+        fs::write(&file, "lock: maybe\n").unwrap();
+        assert!(reload("testing", &sources, &tx));
+        fs::remove_file(&file).unwrap();
+        assert!(reload("testing", &sources, &tx));
+        assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Empty));
+
+        fs::write(&file, "lock: True\n").unwrap();
+        assert!(reload("testing", &sources, &tx));
+        assert!(rx.try_recv().unwrap().lock);
+
+        drop(rx);
+        assert!(!reload("testing", &sources, &tx));
+    }
+}
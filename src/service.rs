@@ -0,0 +1,459 @@
+//! The daemon's D-Bus service on the session bus, for panels and other programs to follow
+//! and control it.
+//!
+//! Calls are answered by asking the main loop, the same as requests on the control socket.
+
+use crate::{
+    control::{self, Control, Request},
+    dbus::{self, Connection, Message, MessageKind, Value, Writer},
+    inhibit::{Inhibit, Process},
+    machine::LockState,
+    status::DaemonStatus,
+};
+use std::{
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The name owned on the session bus, which is also the interface's name
+pub const NAME: &str = "org.xscreensaver.Suspend";
+pub const PATH: &str = "/org/xscreensaver/Suspend";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+
+/// The daemon refused, or couldn't do it
+const FAILED: &str = "org.xscreensaver.Suspend.Error.Failed";
+const INVALID_ARGS: &str = "org.freedesktop.DBus.Error.InvalidArgs";
+const UNKNOWN_METHOD: &str = "org.freedesktop.DBus.Error.UnknownMethod";
+const UNKNOWN_OBJECT: &str = "org.freedesktop.DBus.Error.UnknownObject";
+const UNKNOWN_INTERFACE: &str = "org.freedesktop.DBus.Error.UnknownInterface";
+const UNKNOWN_PROPERTY: &str = "org.freedesktop.DBus.Error.UnknownProperty";
+const READ_ONLY: &str = "org.freedesktop.DBus.Error.PropertyReadOnly";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.xscreensaver.Suspend">
+    <property name="Locked" type="b" access="read"/>
+    <property name="LockedSince" type="t" access="read"/>
+    <property name="NextSuspendAt" type="t" access="read"/>
+    <property name="Inhibitors" type="a(stus)" access="read"/>
+    <method name="Inhibit">
+      <arg name="reason" type="s" direction="in"/>
+      <arg name="seconds" type="t" direction="in"/>
+      <arg name="cookie" type="u" direction="out"/>
+    </method>
+    <method name="Uninhibit">
+      <arg name="cookie" type="u" direction="in"/>
+    </method>
+    <method name="SuspendNow"/>
+    <signal name="WillSuspend">
+      <arg name="action" type="s"/>
+    </signal>
+    <signal name="Resumed">
+      <arg name="asleep" type="t"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <method name="Set">
+      <arg name="interface" type="s" direction="in"/>
+      <arg name="name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="in"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+/// An error reply: its name and message
+type Failure = (&'static str, String);
+
+/// Sends the service's signals, from the main loop
+#[derive(Clone)]
+pub struct Signals(Writer);
+
+impl Signals {
+    /// About to sleep, doing `action`, e.g. suspend
+    pub fn will_suspend(&self, action: &str) {
+        self.emit("WillSuspend", vec![Value::String(action.into())]);
+    }
+
+    /// Woken up after being asleep this long
+    pub fn resumed(&self, asleep: Duration) {
+        self.emit("Resumed", vec![Value::UInt64(asleep.as_secs())]);
+    }
+
+    fn emit(&self, member: &str, body: Vec<Value>) {
+        if let Err(e) = self.0.send(Message::signal(PATH, NAME, member, body)) {
+            eprintln!("Couldn't send the {member} signal: {e}");
+        }
+    }
+}
+
+/// Own `NAME` on `conn` and answer calls in a thread, asking the main loop through `tx`.
+/// `inhibitors` lists what's blocking sleep, for the Inhibitors property.
+pub fn spawn_service<T, F>(
+    mut conn: Connection,
+    tx: Sender<T>,
+    inhibitors: F,
+) -> Result<Signals, dbus::Error>
+where
+    T: From<Control> + Send + 'static,
+    F: Fn() -> Vec<Inhibit> + Send + 'static,
+{
+    conn.request_name(NAME)?;
+    let signals = Signals(conn.writer());
+    let service = Service { tx, inhibitors };
+    thread::spawn(move || loop {
+        let call = match conn.recv(None) {
+            Ok(Some(call)) if call.kind == MessageKind::MethodCall => call,
+            Ok(_) => continue,
+            Err(e) => {
+                eprintln!("Lost the session bus: {e}");
+                return;
+            }
+        };
+        let reply = service.answer(&mut conn, &call);
+        if let Err(e) = conn.send(reply) {
+            eprintln!("Couldn't reply on the session bus: {e}");
+        }
+    });
+    Ok(signals)
+}
+
+struct Service<T, F> {
+    tx: Sender<T>,
+    inhibitors: F,
+}
+
+impl<T, F> Service<T, F>
+where
+    T: From<Control>,
+    F: Fn() -> Vec<Inhibit>,
+{
+    fn answer(&self, conn: &mut Connection, call: &Message) -> Message {
+        match self.dispatch(conn, call) {
+            Ok(body) => Message::method_return(call, body),
+            Err((name, message)) => Message::error(call, name, &message),
+        }
+    }
+
+    fn dispatch(&self, conn: &mut Connection, call: &Message) -> Result<Vec<Value>, Failure> {
+        let member = call.member.as_deref().unwrap_or_default();
+        match (call.path.as_deref(), call.interface.as_deref(), member) {
+            (path, _, _) if path != Some(PATH) => {
+                Err((UNKNOWN_OBJECT, format!("no object at {path:?}")))
+            }
+            (_, Some(INTROSPECTABLE), "Introspect") => {
+                Ok(vec![Value::String(INTROSPECTION.into())])
+            }
+            (_, Some(PROPERTIES), "Get") => match call.body.as_slice() {
+                [Value::String(interface), Value::String(_)] if interface != NAME => {
+                    Err((UNKNOWN_INTERFACE, format!("no interface {interface}")))
+                }
+                [Value::String(_), Value::String(name)] => self
+                    .properties()?
+                    .into_iter()
+                    .find(|(property, _)| property == name)
+                    .map(|(_, value)| vec![Value::Variant(Box::new(value))])
+                    .ok_or_else(|| (UNKNOWN_PROPERTY, format!("no property {name}"))),
+                _ => Err((INVALID_ARGS, "expected an interface and a name".into())),
+            },
+            (_, Some(PROPERTIES), "GetAll") => {
+                let properties = match call.body.as_slice() {
+                    [Value::String(interface)] if interface == NAME => self.properties()?,
+                    [Value::String(_)] => Vec::new(),
+                    _ => return Err((INVALID_ARGS, "expected an interface".into())),
+                };
+                let entries = properties
+                    .into_iter()
+                    .map(|(name, value)| {
+                        Value::DictEntry(
+                            Box::new(Value::String(name.into())),
+                            Box::new(Value::Variant(Box::new(value))),
+                        )
+                    })
+                    .collect();
+                Ok(vec![Value::Array("{sv}".into(), entries)])
+            }
+            (_, Some(PROPERTIES), "Set") => Err((READ_ONLY, "properties are read only".into())),
+            (_, Some(NAME) | None, "Inhibit") => match call.body.as_slice() {
+                [Value::String(reason), Value::UInt64(seconds)] => {
+                    self.inhibit(conn, call.sender.as_deref(), reason, *seconds)
+                }
+                _ => Err((INVALID_ARGS, "expected a reason and seconds".into())),
+            },
+            (_, Some(NAME) | None, "Uninhibit") => match call.body.as_slice() {
+                [Value::UInt32(cookie)] => self
+                    .ask(Request::Uninhibit(vec![cookie.to_string()]))
+                    .map(|_| Vec::new()),
+                _ => Err((INVALID_ARGS, "expected a cookie".into())),
+            },
+            (_, Some(NAME) | None, "SuspendNow") => {
+                self.ask(Request::SuspendNow).map(|_| Vec::new())
+            }
+            (_, interface, _) => Err((
+                UNKNOWN_METHOD,
+                format!("no method {member} in {}", interface.unwrap_or(NAME)),
+            )),
+        }
+    }
+
+    /// Ask the main loop, and wait for its reply
+    fn ask(&self, request: Request) -> Result<String, Failure> {
+        let stopped = || (FAILED, "the daemon stopped".to_string());
+        let (reply, rx) = mpsc::channel();
+        self.tx
+            .send(Control { request, reply }.into())
+            .map_err(|_| stopped())?;
+        rx.recv().map_err(|_| stopped())?.map_err(|e| (FAILED, e))
+    }
+
+    /// Everything in the interface's properties, from what the main loop says it's doing.
+    /// Times are seconds since 1970, or 0 for none.
+    fn properties(&self) -> Result<Vec<(&'static str, Value)>, Failure> {
+        let body = self.ask(Request::Status)?;
+        let status = DaemonStatus::parse(&body).map_err(|e| (FAILED, e.to_string()))?;
+        let inhibitors = (self.inhibitors)()
+            .into_iter()
+            .map(|inhibit| {
+                Value::Struct(vec![
+                    Value::String(inhibit.id),
+                    seconds(inhibit.until),
+                    Value::UInt32(inhibit.process.map_or(0, |process| process.pid)),
+                    Value::String(inhibit.reason),
+                ])
+            })
+            .collect();
+        // It won't sleep then after all
+        let next_suspend = status.next_suspend.filter(|_| !status.paused);
+        Ok(vec![
+            ("Locked", Value::Bool(status.state != LockState::Unlocked)),
+            ("LockedSince", seconds(status.locked_since)),
+            ("NextSuspendAt", seconds(next_suspend)),
+            ("Inhibitors", Value::Array("(stus)".into(), inhibitors)),
+        ])
+    }
+
+    /// Inhibit for `seconds`, or until uninhibited if 0, returning the cookie to uninhibit
+    /// with. It only lasts while the caller runs, so it's not left behind if the caller exits
+    /// without uninhibiting.
+    fn inhibit(
+        &self,
+        conn: &mut Connection,
+        sender: Option<&str>,
+        reason: &str,
+        seconds: u64,
+    ) -> Result<Vec<Value>, Failure> {
+        let pid = match sender {
+            Some(sender) => conn
+                .bus_call(
+                    "GetConnectionUnixProcessID",
+                    vec![Value::String(sender.into())],
+                )
+                .map_err(|e| (FAILED, format!("couldn't find the caller: {e}")))?
+                .first()
+                .and_then(Value::as_u32),
+            None => None,
+        };
+        // Can't be found if it's in another PID namespace
+        let process = pid.and_then(Process::of);
+        let reason = match (reason, &process) {
+            ("", Some(process)) => {
+                let name = process.name().unwrap_or_default();
+                format!("{name} (pid {})", process.pid)
+            }
+            _ => reason.into(),
+        };
+        let until = match seconds {
+            0 => None,
+            seconds => Some(
+                SystemTime::now()
+                    .checked_add(Duration::from_secs(seconds))
+                    .ok_or((INVALID_ARGS, format!("{seconds} seconds is too long")))?,
+            ),
+        };
+        let inhibit = Inhibit {
+            id: String::new(),
+            until,
+            process,
+            reason,
+        };
+        let body = self.ask(Request::Inhibit(inhibit))?;
+        let cookie = control::inhibit_id(&body)
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| (FAILED, format!("no cookie in {body:?}")))?;
+        Ok(vec![Value::UInt32(cookie)])
+    }
+}
+
+/// Seconds since 1970, or 0 for none
+fn seconds(time: Option<SystemTime>) -> Value {
+    let since_epoch = time.and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+    Value::UInt64(since_epoch.map_or(0, |since| since.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dbus::test_bus::TestBus, settings::XscreensaverSettings};
+
+    /// Answer requests like the main loop would, passing them on to the test
+    fn main_loop() -> (Sender<Control>, mpsc::Receiver<Request>) {
+        let (tx, rx) = mpsc::channel::<Control>();
+        let (requests, requested) = mpsc::channel();
+        thread::spawn(move || {
+            for control in rx {
+                let reply = match &control.request {
+                    Request::Status => Ok(DaemonStatus {
+                        pid: 1,
+                        paused: false,
+                        state: LockState::Locked,
+                        locked_since: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                        sleeps: 0,
+                        asleep: Duration::ZERO,
+                        next_suspend: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_600)),
+                        action: "suspend".into(),
+                        settings: XscreensaverSettings::default(),
+                    }
+                    .to_toml()),
+                    Request::Inhibit(_) => Ok("id = \"3\"\n".into()),
+                    Request::SuspendNow => Err("the screen isn't locked".into()),
+                    _ => Ok(String::new()),
+                };
+                requests.send(control.request).unwrap();
+                control.reply.send(reply).unwrap();
+            }
+        });
+        (tx, requested)
+    }
+
+    fn call(conn: &mut Connection, interface: &str, member: &str, body: Vec<Value>) -> Vec<Value> {
+        conn.method_call(NAME, PATH, interface, member, body)
+            .unwrap()
+    }
+
+    #[test]
+    fn answers_calls_and_sends_signals() {
+        let Some(bus) = TestBus::start() else {
+            return;
+        };
+        let (tx, requested) = main_loop();
+        let backup = || Inhibit {
+            id: "backup".into(),
+            until: None,
+            process: None,
+            reason: "nightly backup".into(),
+        };
+        let signals = spawn_service(
+            Connection::open(&bus.address).unwrap(),
+            tx.clone(),
+            move || vec![backup()],
+        )
+        .unwrap();
+        assert!(matches!(
+            spawn_service(Connection::open(&bus.address).unwrap(), tx, Vec::new),
+            Err(dbus::Error::NameTaken(_))
+        ));
+
+        let mut conn = Connection::open(&bus.address).unwrap();
+        let get = |conn: &mut Connection, name: &str| {
+            let body = vec![Value::String(NAME.into()), Value::String(name.into())];
+            call(conn, PROPERTIES, "Get", body)
+        };
+        let variant = |value| vec![Value::Variant(Box::new(value))];
+        assert_eq!(get(&mut conn, "Locked"), variant(Value::Bool(true)));
+        assert_eq!(
+            get(&mut conn, "NextSuspendAt"),
+            variant(Value::UInt64(1_700_000_600))
+        );
+        assert_eq!(
+            get(&mut conn, "Inhibitors"),
+            variant(Value::Array(
+                "(stus)".into(),
+                vec![Value::Struct(vec![
+                    Value::String("backup".into()),
+                    Value::UInt64(0),
+                    Value::UInt32(0),
+                    Value::String("nightly backup".into()),
+                ])]
+            ))
+        );
+        let all = call(
+            &mut conn,
+            PROPERTIES,
+            "GetAll",
+            vec![Value::String(NAME.into())],
+        );
+        assert_eq!(all[0].signature(), "a{sv}");
+        assert_eq!(all[0].as_slice().unwrap().len(), 4);
+        assert_eq!(requested.try_iter().count(), 4);
+
+        let reason = Value::String("testing".into());
+        let cookie = call(&mut conn, NAME, "Inhibit", vec![reason, Value::UInt64(60)]);
+        assert_eq!(cookie, vec![Value::UInt32(3)]);
+        let Ok(Request::Inhibit(inhibit)) = requested.recv() else {
+            panic!("expected an inhibit");
+        };
+        assert_eq!(inhibit.reason, "testing");
+        assert_eq!(inhibit.process, Process::of(std::process::id()));
+        assert!(inhibit.until > Some(SystemTime::now() + Duration::from_secs(50)));
+        let forever = vec![Value::String("forever".into()), Value::UInt64(u64::MAX)];
+        assert!(matches!(
+            conn.method_call(NAME, PATH, NAME, "Inhibit", forever),
+            Err(dbus::Error::Remote { name, .. }) if name == INVALID_ARGS
+        ));
+
+        call(&mut conn, NAME, "Uninhibit", cookie);
+        assert_eq!(
+            requested.recv().unwrap(),
+            Request::Uninhibit(vec!["3".into()])
+        );
+        assert!(matches!(
+            conn.method_call(NAME, PATH, NAME, "SuspendNow", vec![]),
+            Err(dbus::Error::Remote { name, message })
+                if name == FAILED && message == "the screen isn't locked"
+        ));
+        assert!(matches!(
+            conn.method_call(NAME, PATH, NAME, "Sleep", vec![]),
+            Err(dbus::Error::Remote { name, .. }) if name == UNKNOWN_METHOD
+        ));
+
+        let rule = format!("type='signal',interface='{NAME}'");
+        conn.bus_call("AddMatch", vec![Value::String(rule)])
+            .unwrap();
+        signals.will_suspend("suspend");
+        signals.resumed(Duration::from_secs(90));
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let message = conn.recv(Some(Duration::from_secs(5))).unwrap().unwrap();
+            if message.kind == MessageKind::Signal && message.interface.as_deref() == Some(NAME) {
+                received.push((message.member.unwrap(), message.body));
+            }
+        }
+        assert_eq!(
+            received,
+            [
+                (
+                    "WillSuspend".to_string(),
+                    vec![Value::String("suspend".into())]
+                ),
+                ("Resumed".to_string(), vec![Value::UInt64(90)]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,598 @@
+//! xscreensaver's own settings
+
+use crate::{
+    supervise::Child,
+    xresources::{self, ErrorKind, ParseError, Resource},
+};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    time::Duration,
+};
+
+/// Resource class xscreensaver's settings are under
+pub const CLASS: &str = "XScreenSaver";
+/// Where distributions install xscreensaver's app-defaults file
+const APP_DEFAULTS: [&str; 3] = [
+    "/usr/share/X11/app-defaults/XScreenSaver",
+    "/etc/X11/app-defaults/XScreenSaver",
+    "/usr/lib/X11/app-defaults/XScreenSaver",
+];
+/// Longest to wait for `xrdb -query`
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Settings from ~/.xscreensaver
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct XscreensaverSettings {
+    /// How long until the screen blanks
+    pub timeout: Duration,
+    /// How often to change display hacks
+    pub cycle: Duration,
+    /// Lock the screen when it blanks
+    pub lock: bool,
+    /// How long after blanking until it locks
+    pub lock_timeout: Duration,
+    /// How long should a password dialog box be left on the screen
+    pub password_timeout: Duration,
+    pub visual_id: String,
+    pub install_colormap: bool,
+    pub verbose: bool,
+    /// Show the splash screen at startup
+    pub splash: bool,
+    pub splash_duration: Duration,
+    /// Run four hacks at once
+    pub quad: bool,
+    pub demo_command: String,
+    pub prefs_command: String,
+    pub new_login_command: String,
+    pub help_url: String,
+    pub load_url: String,
+    /// Scheduling priority of hacks
+    pub nice: i32,
+    pub memory_limit: String,
+    /// Fade to black when blanking
+    pub fade: bool,
+    /// Fade in when unblanking
+    pub unfade: bool,
+    pub fade_seconds: Duration,
+    pub fade_ticks: u32,
+    pub capture_stderr: bool,
+    pub ignore_uninstalled_programs: bool,
+    pub font: String,
+    /// Is DPMS enabled
+    pub dpms_enabled: bool,
+    /// Turn the monitor off straight away in blank-only mode
+    pub dpms_quick_off: bool,
+    /// How long until DPMS standby
+    pub dpms_standby: Duration,
+    /// How long until DPMS suspend
+    pub dpms_suspend: Duration,
+    /// How long until DPMS activates
+    pub dpms_off: Duration,
+    pub grab_desktop_images: bool,
+    pub grab_video_frames: bool,
+    pub choose_random_images: bool,
+    pub image_directory: String,
+    pub mode: Mode,
+    /// Index of the hack used in `one` mode
+    pub selected: i32,
+    /// What the text hacks display: date, literal, file, program or url
+    pub text_mode: String,
+    pub text_literal: String,
+    pub text_file: String,
+    pub text_program: String,
+    pub text_url: String,
+    pub dialog_theme: String,
+    pub settings_geom: String,
+    pub pointer_hysteresis: u32,
+    pub auth_warning_slack: u32,
+    /// Display hacks to choose from
+    pub programs: Vec<Program>,
+    /// Settings we don't know about, by name
+    pub other: BTreeMap<String, String>,
+}
+
+/// Which display hacks run
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Random,
+    /// The same random hack on every screen
+    RandomSame,
+    /// Only the selected hack
+    One,
+    /// Just blank the screen
+    Blank,
+    /// Don't blank at all, DPMS still happens
+    Off,
+}
+
+/// An entry in the `programs` list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// False if the entry starts with `-`
+    pub enabled: bool,
+    /// Visual to run on, e.g. `GL`
+    pub visual: Option<String>,
+    /// Name shown in the settings, if it's different from the command
+    pub name: Option<String>,
+    pub command: String,
+}
+
+/// ~/.xscreensaver, if HOME is set
+pub fn user_file() -> Option<PathBuf> {
+    Some(crate::home_dir()?.join(".xscreensaver"))
+}
+
+/// Somewhere xscreensaver reads its settings from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The app-defaults file installed with xscreensaver
+    AppDefaults(PathBuf),
+    /// The X resource database loaded with `xrdb`, read by running this binary
+    Xrdb(PathBuf),
+    /// ~/.xscreensaver
+    File(PathBuf),
+}
+
+impl Source {
+    /// Where xscreensaver looks, lowest precedence first
+    pub fn all(xrdb: &Path) -> Vec<Source> {
+        let mut sources: Vec<Source> = APP_DEFAULTS
+            .iter()
+            .map(Path::new)
+            .find(|path| path.exists())
+            .map(|path| Source::AppDefaults(path.into()))
+            .into_iter()
+            .collect();
+        if std::env::var_os("DISPLAY").is_some() {
+            sources.push(Source::Xrdb(xrdb.into()));
+        }
+        sources.extend(user_file().map(Source::File));
+        sources
+    }
+
+    /// The resource file text, or None if this source doesn't exist. xrdb failing, say
+    /// because it isn't installed, counts as no resources rather than an error.
+    fn read(&self) -> Result<Option<String>, Error> {
+        match self {
+            Source::AppDefaults(path) | Source::File(path) => match std::fs::read_to_string(path) {
+                Ok(text) => Ok(Some(text)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(Error::Io(self.clone(), e)),
+            },
+            Source::Xrdb(xrdb) => {
+                match Child::output(Command::new(xrdb).arg("-query"), QUERY_TIMEOUT) {
+                    Ok(output) if output.status.success() => Ok(Some(output.stdout)),
+                    Ok(output) => {
+                        eprintln!("Ignoring {self}: exited with {}", output.status);
+                        Ok(None)
+                    }
+                    Err(e) => {
+                        eprintln!("Ignoring {self}: {e}");
+                        Ok(None)
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl XscreensaverSettings {
+    /// Load settings with the same precedence as xscreensaver: the app-defaults file,
+    /// overridden by resources loaded with `xrdb`, overridden by ~/.xscreensaver
+    pub fn load(xrdb: &Path) -> Result<Self, Error> {
+        XscreensaverSettings::load_from(&Source::all(xrdb))
+    }
+
+    /// Load settings from each source in turn, skipping any that don't exist
+    pub fn load_from(sources: &[Source]) -> Result<Self, Error> {
+        let mut settings = XscreensaverSettings::default();
+        let mut found = false;
+        for source in sources {
+            let Some(text) = source.read()? else {
+                continue;
+            };
+            found = true;
+            xresources::parse(&text)
+                .and_then(|resources| settings.apply(&resources))
+                .map_err(|e| Error::Parse(source.clone(), e))?;
+        }
+        if !found {
+            return Err(Error::NotFound(sources.to_vec()));
+        }
+        Ok(settings)
+    }
+
+    /// Update settings from resources, ignoring those for other applications
+    pub fn apply(&mut self, resources: &[Resource]) -> Result<(), ParseError> {
+        for resource in resources {
+            if let Some(name) = resource.setting(CLASS) {
+                self.set(name, &resource.value).map_err(|kind| ParseError {
+                    line: resource.line,
+                    kind,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set one setting by its resource name
+    fn set(&mut self, name: &str, value: &str) -> Result<(), ErrorKind> {
+        let bool = || {
+            parse_bool(value).ok_or_else(|| ErrorKind::InvalidBool {
+                name: name.into(),
+                value: value.into(),
+            })
+        };
+        let time = |unit| {
+            parse_time(value, unit).ok_or_else(|| ErrorKind::InvalidTime {
+                name: name.into(),
+                value: value.into(),
+            })
+        };
+        let minutes = || time(60);
+        let seconds = || time(1);
+        let string = || value.to_string();
+        fn number<T: FromStr>(name: &str, value: &str) -> Result<T, ErrorKind> {
+            value.trim().parse().map_err(|_| ErrorKind::InvalidNumber {
+                name: name.into(),
+                value: value.into(),
+            })
+        }
+        match name {
+            "timeout" => self.timeout = minutes()?,
+            "cycle" => self.cycle = minutes()?,
+            "lock" => self.lock = bool()?,
+            "lockTimeout" => self.lock_timeout = minutes()?,
+            "passwdTimeout" => self.password_timeout = seconds()?,
+            "visualID" => self.visual_id = string(),
+            "installColormap" => self.install_colormap = bool()?,
+            "verbose" => self.verbose = bool()?,
+            "splash" => self.splash = bool()?,
+            "splashDuration" => self.splash_duration = seconds()?,
+            "quad" => self.quad = bool()?,
+            "demoCommand" => self.demo_command = string(),
+            "prefsCommand" => self.prefs_command = string(),
+            "newLoginCommand" => self.new_login_command = string(),
+            "helpURL" => self.help_url = string(),
+            "loadURL" => self.load_url = string(),
+            "nice" => self.nice = number(name, value)?,
+            "memoryLimit" => self.memory_limit = string(),
+            "fade" => self.fade = bool()?,
+            "unfade" => self.unfade = bool()?,
+            "fadeSeconds" => self.fade_seconds = seconds()?,
+            "fadeTicks" => self.fade_ticks = number(name, value)?,
+            "captureStderr" => self.capture_stderr = bool()?,
+            "ignoreUninstalledPrograms" => self.ignore_uninstalled_programs = bool()?,
+            "font" => self.font = string(),
+            "dpmsEnabled" => self.dpms_enabled = bool()?,
+            "dpmsQuickOff" => self.dpms_quick_off = bool()?,
+            "dpmsStandby" => self.dpms_standby = minutes()?,
+            "dpmsSuspend" => self.dpms_suspend = minutes()?,
+            "dpmsOff" => self.dpms_off = minutes()?,
+            "grabDesktopImages" => self.grab_desktop_images = bool()?,
+            "grabVideoFrames" => self.grab_video_frames = bool()?,
+            "chooseRandomImages" => self.choose_random_images = bool()?,
+            "imageDirectory" => self.image_directory = string(),
+            "mode" => {
+                self.mode = value.parse().map_err(|_| ErrorKind::InvalidChoice {
+                    name: name.into(),
+                    value: value.into(),
+                })?
+            }
+            "selected" => self.selected = number(name, value)?,
+            "textMode" => self.text_mode = string(),
+            "textLiteral" => self.text_literal = string(),
+            "textFile" => self.text_file = string(),
+            "textProgram" => self.text_program = string(),
+            "textURL" => self.text_url = string(),
+            "dialogTheme" => self.dialog_theme = string(),
+            "settingsGeom" => self.settings_geom = string(),
+            "pointerHysteresis" => self.pointer_hysteresis = number(name, value)?,
+            "authWarningSlack" => self.auth_warning_slack = number(name, value)?,
+            "programs" => self.programs = value.lines().filter_map(Program::parse).collect(),
+            _ => {
+                self.other.insert(name.into(), string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a boolean the way Xt does
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "on" | "yes" => Some(true),
+        "false" | "off" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse `hh:mm:ss` or `mm:ss`, or a bare number of `unit` seconds.
+/// xscreensaver reads bare numbers as minutes for some settings and seconds for others.
+fn parse_time(value: &str, unit: u64) -> Option<Duration> {
+    let parts = value
+        .trim()
+        .split(':')
+        .map(|n| n.trim().parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let seconds = match parts[..] {
+        [n] => n.checked_mul(unit)?,
+        [m, s] => m.checked_mul(60)?.checked_add(s)?,
+        [h, m, s] => h
+            .checked_mul(60 * 60)?
+            .checked_add(m.checked_mul(60)?)?
+            .checked_add(s)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(seconds))
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "random" => Ok(Mode::Random),
+            "random-same" => Ok(Mode::RandomSame),
+            "one" => Ok(Mode::One),
+            "blank" => Ok(Mode::Blank),
+            "off" => Ok(Mode::Off),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Program {
+    /// Parse `[-] [visual:] ["name"] command`, None for blank lines
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let (enabled, line) = match line.strip_prefix('-') {
+            Some(rest) => (false, rest.trim_start()),
+            None => (true, line),
+        };
+        let first = line.split_whitespace().next()?;
+        let (visual, line) = match first.strip_suffix(':') {
+            Some(visual) if !visual.is_empty() => {
+                (Some(visual.to_string()), line[first.len()..].trim_start())
+            }
+            _ => (None, line),
+        };
+        let (name, command) = match line.strip_prefix('"').and_then(|rest| rest.split_once('"')) {
+            Some((name, command)) => (Some(name.to_string()), command.trim()),
+            None => (None, line),
+        };
+        (!command.is_empty()).then(|| Program {
+            enabled,
+            visual,
+            name,
+            command: command.into(),
+        })
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::AppDefaults(path) | Source::File(path) => write!(f, "{}", path.display()),
+            Source::Xrdb(xrdb) => write!(f, "{} -query", xrdb.display()),
+        }
+    }
+}
+
+/// Why the settings couldn't be loaded
+#[derive(Debug)]
+pub enum Error {
+    Io(Source, io::Error),
+    Parse(Source, ParseError),
+    /// None of the sources had any settings
+    NotFound(Vec<Source>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(source, e) => write!(f, "{source}: {e}"),
+            Error::Parse(source, e) => write!(f, "{source}: {e}"),
+            Error::NotFound(sources) => {
+                write!(f, "no xscreensaver settings found in ")?;
+                let sources: Vec<String> = sources.iter().map(Source::to_string).collect();
+                write!(f, "{}", sources.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    fn settings(text: &str) -> Result<XscreensaverSettings, ParseError> {
+        let mut settings = XscreensaverSettings::default();
+        settings.apply(&xresources::parse(text)?)?;
+        Ok(settings)
+    }
+
+    #[test]
+    fn parses_times() {
+        let secs = |s| Some(Duration::from_secs(s));
+        assert_eq!(parse_time("15", 60), secs(900));
+        assert_eq!(parse_time("30", 1), secs(30));
+        assert_eq!(parse_time("1:30", 60), secs(90));
+        assert_eq!(parse_time(" 1:00:05 ", 60), secs(3605));
+        assert_eq!(parse_time("", 60), None);
+        assert_eq!(parse_time("ten", 60), None);
+        assert_eq!(parse_time("-1", 60), None);
+        assert_eq!(parse_time("1:2:3:4", 60), None);
+    }
+
+    #[test]
+    fn rejects_times_too_long_to_count() {
+        assert_eq!(parse_time("999999999999999999:00", 60), None);
+        assert_eq!(parse_time("9999999999999999:00:00", 60), None);
+        assert_eq!(parse_time("0:999999999999999999:00", 60), None);
+        assert_eq!(parse_time("18446744073709551615", 60), None);
+        assert_eq!(
+            settings("*dpmsOff: 999999999999999999:00\n"),
+            Err(ParseError {
+                line: 1,
+                kind: ErrorKind::InvalidTime {
+                    name: "dpmsOff".into(),
+                    value: "999999999999999999:00".into(),
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn parses_programs() {
+        assert_eq!(
+            Program::parse(r#"-  GL: "Moebius Gears" moebiusgears -root"#),
+            Some(Program {
+                enabled: false,
+                visual: Some("GL".into()),
+                name: Some("Moebius Gears".into()),
+                command: "moebiusgears -root".into(),
+            })
+        );
+        assert_eq!(
+            Program::parse("  xmatrix -root  "),
+            Some(Program {
+                enabled: true,
+                visual: None,
+                name: None,
+                command: "xmatrix -root".into(),
+            })
+        );
+        assert_eq!(Program::parse("   "), None);
+        assert_eq!(Program::parse(r#""Just a name""#), None);
+    }
+
+    #[test]
+    fn applies_resources_for_xscreensaver() {
+        let settings = settings(
+            "*dpmsOff: 0:15:00\n\
+             XScreenSaver.passwdTimeout: 0:00:30\n\
+             xscreensaver.lock: True\n\
+             XScreenSaver.Dialog.font: fixed\n\
+             Emacs.font: fixed\n\
+             *newSetting: 1\n\
+             *mode: one\n\
+             *programs: xmatrix -root \\n\\\n - GL: gears -root\n",
+        )
+        .unwrap();
+        assert_eq!(settings.dpms_off, Duration::from_secs(900));
+        assert_eq!(settings.password_timeout, Duration::from_secs(30));
+        assert!(settings.lock);
+        assert_eq!(settings.font, "");
+        assert_eq!(settings.mode, Mode::One);
+        assert_eq!(
+            settings.other.get("newSetting").map(String::as_str),
+            Some("1")
+        );
+        let commands: Vec<&str> = settings
+            .programs
+            .iter()
+            .map(|p| p.command.as_str())
+            .collect();
+        assert_eq!(commands, ["xmatrix -root", "gears -root"]);
+    }
+
+    #[test]
+    fn reports_bad_values_with_their_line() {
+        let error = |text| settings(text).unwrap_err();
+        assert_eq!(
+            error("*lock: True\n*lock: maybe\n"),
+            ParseError {
+                line: 2,
+                kind: ErrorKind::InvalidBool {
+                    name: "lock".into(),
+                    value: "maybe".into(),
+                },
+            }
+        );
+        assert_eq!(
+            error("*nice: 99999999999\n").kind,
+            ErrorKind::InvalidNumber {
+                name: "nice".into(),
+                value: "99999999999".into(),
+            }
+        );
+        assert_eq!(
+            error("! comment\n\n*mode: sometimes\n"),
+            ParseError {
+                line: 3,
+                kind: ErrorKind::InvalidChoice {
+                    name: "mode".into(),
+                    value: "sometimes".into(),
+                },
+            }
+        );
+    }
+
+    /// An xrdb that prints these resources, or fails if None
+    fn xrdb(dir: &TempDir, resources: Option<&str>) -> Source {
+        let path = dir.path().join("xrdb");
+        let script = match resources {
+            Some(resources) => format!("#!/bin/sh\ncat <<'EOF'\n{resources}EOF\n"),
+            None => "#!/bin/sh\nexit 1\n".into(),
+        };
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        Source::Xrdb(path)
+    }
+
+    #[test]
+    fn loads_sources_in_order_of_precedence() {
+        let dir = TempDir::new("settings");
+        let app_defaults = dir.path().join("XScreenSaver");
+        fs::write(
+            &app_defaults,
+            "*timeout: 10\n*lock: False\n*dpmsOff: 4:00:00\n",
+        )
+        .unwrap();
+        let user_file = dir.path().join(".xscreensaver");
+        fs::write(&user_file, "timeout: 0:05:00\n").unwrap();
+        let sources = [
+            Source::AppDefaults(app_defaults),
+            xrdb(
+                &dir,
+                Some("XScreenSaver.timeout: 20\nXScreenSaver.lock: True\n"),
+            ),
+            Source::File(user_file),
+        ];
+        let settings = XscreensaverSettings::load_from(&sources).unwrap();
+        // ~/.xscreensaver over xrdb over app-defaults
+        assert_eq!(settings.timeout, Duration::from_secs(5 * 60));
+        assert!(settings.lock);
+        assert_eq!(settings.dpms_off, Duration::from_secs(4 * 60 * 60));
+    }
+
+    #[test]
+    fn skips_missing_sources_and_failing_xrdb() {
+        let dir = TempDir::new("settings");
+        let user_file = dir.path().join(".xscreensaver");
+        fs::write(&user_file, "lock: True\n").unwrap();
+        let sources = [
+            Source::AppDefaults(dir.path().join("missing")),
+            xrdb(&dir, None),
+            Source::Xrdb(dir.path().join("no-xrdb")),
+            Source::File(user_file),
+        ];
+        assert!(XscreensaverSettings::load_from(&sources).unwrap().lock);
+
+        let missing = [Source::File(dir.path().join("missing")), xrdb(&dir, None)];
+        assert!(matches!(
+            XscreensaverSettings::load_from(&missing),
+            Err(Error::NotFound(sources)) if sources == missing
+        ));
+    }
+}
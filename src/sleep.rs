@@ -0,0 +1,254 @@
+//! The ways we can put the machine to sleep
+
+use crate::{
+    config::format_duration,
+    login1::{self, Login1},
+    supervise::{self, Child},
+};
+use std::{
+    ffi::OsStr,
+    fmt, io,
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Longest a command can take to sleep, not counting time asleep
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// What to do once the screen has been locked long enough
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SleepAction {
+    #[default]
+    Suspend,
+    Hibernate,
+    HybridSleep,
+    SuspendThenHibernate,
+    PowerOff,
+    /// Write a state such as `mem` or `disk` to /sys/power/state, without systemd
+    SysPowerState(String),
+    /// Run a command, sleeping is up to it
+    Command(Vec<String>),
+}
+
+/// How the systemd actions are requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// logind's D-Bus API
+    #[default]
+    Logind,
+    /// `systemctl <verb>`
+    Systemctl,
+}
+
+impl SleepAction {
+    /// Go to sleep, running `systemctl` for the systemctl backend. States are written to
+    /// `<sysfs>/power/state`.
+    pub fn perform(&self, backend: Backend, systemctl: &Path, sysfs: &Path) -> Result<(), Error> {
+        let method = match self {
+            SleepAction::Suspend => "Suspend",
+            SleepAction::Hibernate => "Hibernate",
+            SleepAction::HybridSleep => "HybridSleep",
+            SleepAction::SuspendThenHibernate => "SuspendThenHibernate",
+            SleepAction::PowerOff => "PowerOff",
+            SleepAction::SysPowerState(state) => {
+                let path = sysfs.join("power/state");
+                return std::fs::write(&path, state)
+                    .map_err(|e| Error::Io(path.display().to_string(), e));
+            }
+            SleepAction::Command(argv) => return run(argv),
+        };
+        match backend {
+            Backend::Logind => Ok(Login1::system()?.sleep(method)?),
+            Backend::Systemctl => run(&[systemctl.as_os_str(), OsStr::new(&self.to_string())]),
+        }
+    }
+
+    /// The kind of logind inhibitor lock that stops this action
+    pub fn inhibited_by(&self) -> &'static str {
+        match self {
+            SleepAction::PowerOff => "shutdown",
+            _ => "sleep",
+        }
+    }
+}
+
+/// Run a command to completion, killing it if it takes too long
+fn run<S: AsRef<OsStr>>(argv: &[S]) -> Result<(), Error> {
+    let (program, args) = argv.split_first().ok_or(Error::EmptyCommand)?;
+    let program = program.as_ref();
+    let name = || program.to_string_lossy().into_owned();
+    let child = Child::spawn(Command::new(program).args(args).stdin(Stdio::null()))
+        .map_err(|e| Error::Io(name(), e))?;
+    match child.wait(Some(COMMAND_TIMEOUT)) {
+        Ok(exit) if exit.status.success() => Ok(()),
+        Ok(exit) => Err(Error::Command(name(), exit.status)),
+        Err(supervise::Error::Io(e)) => Err(Error::Io(name(), e)),
+        Err(supervise::Error::TimedOut(timeout)) => Err(Error::TimedOut(name(), timeout)),
+    }
+}
+
+/// Parses the systemd names, `mem`/`disk`/`freeze`/`standby` for /sys/power/state
+impl FromStr for SleepAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "suspend" => Ok(SleepAction::Suspend),
+            "hibernate" => Ok(SleepAction::Hibernate),
+            "hybrid-sleep" => Ok(SleepAction::HybridSleep),
+            "suspend-then-hibernate" => Ok(SleepAction::SuspendThenHibernate),
+            "poweroff" => Ok(SleepAction::PowerOff),
+            "mem" | "disk" | "freeze" | "standby" => Ok(SleepAction::SysPowerState(s.into())),
+            _ => Err(format!(
+                "unknown sleep action {s:?}, expected suspend, hibernate, hybrid-sleep, \
+                 suspend-then-hibernate, poweroff, mem, disk, freeze, standby, or a command as an array"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SleepAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SleepAction::Suspend => write!(f, "suspend"),
+            SleepAction::Hibernate => write!(f, "hibernate"),
+            SleepAction::HybridSleep => write!(f, "hybrid-sleep"),
+            SleepAction::SuspendThenHibernate => write!(f, "suspend-then-hibernate"),
+            SleepAction::PowerOff => write!(f, "poweroff"),
+            SleepAction::SysPowerState(state) => write!(f, "{state}"),
+            SleepAction::Command(argv) => write!(f, "{}", argv.join(" ")),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "logind" => Ok(Backend::Logind),
+            "systemctl" => Ok(Backend::Systemctl),
+            _ => Err(format!(
+                "unknown backend {s:?}, expected logind or systemctl"
+            )),
+        }
+    }
+}
+
+/// Why we couldn't sleep
+#[derive(Debug)]
+pub enum Error {
+    Logind(login1::Error),
+    /// Couldn't run a command or write a file
+    Io(String, io::Error),
+    /// A command failed
+    Command(String, std::process::ExitStatus),
+    /// A command ran for longer than this, and was killed
+    TimedOut(String, Duration),
+    /// The command to run was empty
+    EmptyCommand,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Logind(e) => write!(f, "{e}"),
+            Error::Io(what, e) => write!(f, "{what}: {e}"),
+            Error::Command(program, status) => write!(f, "{program} failed: {status}"),
+            Error::TimedOut(program, timeout) => write!(
+                f,
+                "{program} killed after running for {}",
+                format_duration(*timeout)
+            ),
+            Error::EmptyCommand => write!(f, "no command to run"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<login1::Error> for Error {
+    fn from(e: login1::Error) -> Self {
+        Error::Logind(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TempDir;
+    use std::fs;
+
+    #[test]
+    fn round_trips_through_strings() {
+        for name in [
+            "suspend",
+            "hibernate",
+            "hybrid-sleep",
+            "suspend-then-hibernate",
+            "poweroff",
+            "mem",
+            "disk",
+            "freeze",
+            "standby",
+        ] {
+            assert_eq!(name.parse::<SleepAction>().unwrap().to_string(), name);
+        }
+        assert_eq!("mem".parse(), Ok(SleepAction::SysPowerState("mem".into())));
+        assert!("nap"
+            .parse::<SleepAction>()
+            .unwrap_err()
+            .starts_with("unknown sleep action \"nap\""));
+        assert_eq!("systemctl".parse(), Ok(Backend::Systemctl));
+        assert!("dbus".parse::<Backend>().is_err());
+    }
+
+    #[test]
+    fn writes_the_power_state() {
+        let sysfs = TempDir::new("sleep");
+        fs::create_dir(sysfs.path().join("power")).unwrap();
+        SleepAction::SysPowerState("mem".into())
+            .perform(Backend::Logind, Path::new("systemctl"), sysfs.path())
+            .unwrap();
+        let state = fs::read_to_string(sysfs.path().join("power/state")).unwrap();
+        assert_eq!(state, "mem");
+
+        // No power/state to write to
+        assert!(matches!(
+            SleepAction::SysPowerState("disk".into()).perform(
+                Backend::Logind,
+                Path::new("systemctl"),
+                &sysfs.path().join("missing")
+            ),
+            Err(Error::Io(..))
+        ));
+    }
+
+    #[test]
+    fn runs_commands() {
+        let perform = |action: SleepAction, systemctl: &str| {
+            action.perform(Backend::Systemctl, Path::new(systemctl), Path::new("/sys"))
+        };
+        let sh =
+            |script: &str| SleepAction::Command(vec!["/bin/sh".into(), "-c".into(), script.into()]);
+        perform(sh("exit 0"), "systemctl").unwrap();
+        assert!(matches!(
+            perform(sh("exit 3"), "systemctl"),
+            Err(Error::Command(program, status))
+                if program == "/bin/sh" && status.code() == Some(3)
+        ));
+        assert!(matches!(
+            perform(SleepAction::Command(Vec::new()), "systemctl"),
+            Err(Error::EmptyCommand)
+        ));
+
+        // The systemctl backend runs `systemctl <verb>`
+        perform(SleepAction::Suspend, "/bin/true").unwrap();
+        assert!(matches!(
+            perform(SleepAction::Hibernate, "/bin/false"),
+            Err(Error::Command(program, _)) if program == "/bin/false"
+        ));
+    }
+}
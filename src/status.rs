@@ -0,0 +1,400 @@
+//! What the daemon is doing, for `xscreensaver-suspend status`
+
+use crate::{
+    config::format_duration,
+    inhibit::Inhibit,
+    login1::Inhibitor,
+    machine::LockState,
+    settings::XscreensaverSettings,
+    toml::{self, Value},
+};
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// What the daemon says it's doing when asked over its control socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    /// Not sleeping until resumed
+    pub paused: bool,
+    pub state: LockState,
+    pub locked_since: Option<SystemTime>,
+    /// How many times it's slept since locking, and for how long
+    pub sleeps: u32,
+    pub asleep: Duration,
+    /// When it will next try to sleep
+    pub next_suspend: Option<SystemTime>,
+    /// What it will do then, e.g. suspend
+    pub action: String,
+    /// The xscreensaver settings it's using. Only the ones that matter for sleeping are kept.
+    pub settings: XscreensaverSettings,
+}
+
+impl DaemonStatus {
+    /// Parse what `to_toml` wrote
+    pub fn parse(text: &str) -> Result<Self, toml::Error> {
+        let mut status = DaemonStatus {
+            pid: 0,
+            paused: false,
+            state: LockState::Unlocked,
+            locked_since: None,
+            sleeps: 0,
+            asleep: Duration::ZERO,
+            next_suspend: None,
+            action: String::new(),
+            settings: XscreensaverSettings::default(),
+        };
+        for (key, (line, value)) in toml::parse(text)? {
+            let error = |message: &str| toml::Error {
+                line,
+                message: format!("{key} {message}"),
+            };
+            let number = || match value {
+                Value::Integer(n) => u64::try_from(n).map_err(|_| error("can't be negative")),
+                _ => Err(error("should be a number")),
+            };
+            let secs = || number().map(Duration::from_secs);
+            let flag = || match value {
+                Value::Boolean(b) => Ok(b),
+                _ => Err(error("should be true or false")),
+            };
+            let string = || match &value {
+                Value::String(s) => Ok(s.clone()),
+                _ => Err(error("should be a string")),
+            };
+            let settings = &mut status.settings;
+            match key.as_str() {
+                "pid" => status.pid = number()? as u32,
+                "paused" => status.paused = flag()?,
+                "state" => {
+                    status.state = match string()?.as_str() {
+                        "unlocked" => LockState::Unlocked,
+                        "locked" => LockState::Locked,
+                        "retrying" => LockState::Retrying,
+                        "warning" => LockState::Warning,
+                        _ => return Err(error("isn't a known state")),
+                    }
+                }
+                "locked_since" => status.locked_since = Some(UNIX_EPOCH + secs()?),
+                "sleeps" => status.sleeps = number()? as u32,
+                "asleep" => status.asleep = secs()?,
+                "next_suspend" => status.next_suspend = Some(UNIX_EPOCH + secs()?),
+                "action" => status.action = string()?,
+                "settings.timeout" => settings.timeout = secs()?,
+                "settings.lock" => settings.lock = flag()?,
+                "settings.lock_timeout" => settings.lock_timeout = secs()?,
+                "settings.password_timeout" => settings.password_timeout = secs()?,
+                "settings.dpms_enabled" => settings.dpms_enabled = flag()?,
+                "settings.dpms_standby" => settings.dpms_standby = secs()?,
+                "settings.dpms_suspend" => settings.dpms_suspend = secs()?,
+                "settings.dpms_off" => settings.dpms_off = secs()?,
+                _ => return Err(error("isn't a known key")),
+            }
+        }
+        Ok(status)
+    }
+
+    /// As TOML, with times in seconds since 1970 and durations in seconds
+    pub fn to_toml(&self) -> String {
+        let mut text = format!(
+            "pid = {}\npaused = {}\nstate = \"{}\"\n",
+            self.pid,
+            self.paused,
+            self.state.as_str()
+        );
+        if let Some(since) = self.locked_since {
+            text += &format!("locked_since = {}\n", epoch_secs(since));
+        }
+        text += &format!(
+            "sleeps = {}\nasleep = {}\n",
+            self.sleeps,
+            self.asleep.as_secs()
+        );
+        if let Some(at) = self.next_suspend {
+            text += &format!("next_suspend = {}\n", epoch_secs(at));
+        }
+        text += &format!("action = {}\n", Value::String(self.action.clone()));
+        let s = &self.settings;
+        text += &format!(
+            "\n[settings]\ntimeout = {}\nlock = {}\nlock_timeout = {}\npassword_timeout = {}\n\
+             dpms_enabled = {}\ndpms_standby = {}\ndpms_suspend = {}\ndpms_off = {}\n",
+            s.timeout.as_secs(),
+            s.lock,
+            s.lock_timeout.as_secs(),
+            s.password_timeout.as_secs(),
+            s.dpms_enabled,
+            s.dpms_standby.as_secs(),
+            s.dpms_suspend.as_secs(),
+            s.dpms_off.as_secs(),
+        );
+        text
+    }
+}
+
+/// Everything `xscreensaver-suspend status` shows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// None if the daemon isn't running
+    pub daemon: Option<DaemonStatus>,
+    pub inhibits: Vec<Inhibit>,
+    pub logind: Vec<Inhibitor>,
+}
+
+impl Status {
+    /// The same as JSON, with times in seconds since 1970 and durations in seconds
+    pub fn to_json(&self) -> String {
+        let now = SystemTime::now();
+        let mut fields = vec![("running", self.daemon.is_some().to_string())];
+        if let Some(daemon) = &self.daemon {
+            let s = &daemon.settings;
+            fields.extend([
+                ("pid", daemon.pid.to_string()),
+                ("paused", daemon.paused.to_string()),
+                ("state", json_string(daemon.state.as_str())),
+                (
+                    "locked_since",
+                    json_option(daemon.locked_since.map(epoch_secs)),
+                ),
+                (
+                    "locked_for",
+                    json_option(daemon.locked_since.map(|since| secs_between(since, now))),
+                ),
+                ("sleeps", daemon.sleeps.to_string()),
+                ("asleep", daemon.asleep.as_secs().to_string()),
+                (
+                    "next_suspend",
+                    json_option(daemon.next_suspend.map(epoch_secs)),
+                ),
+                (
+                    "next_suspend_in",
+                    json_option(daemon.next_suspend.map(|at| secs_between(now, at))),
+                ),
+                ("action", json_string(&daemon.action)),
+                (
+                    "settings",
+                    json_object(vec![
+                        ("timeout", s.timeout.as_secs().to_string()),
+                        ("lock", s.lock.to_string()),
+                        ("lock_timeout", s.lock_timeout.as_secs().to_string()),
+                        ("password_timeout", s.password_timeout.as_secs().to_string()),
+                        ("dpms_enabled", s.dpms_enabled.to_string()),
+                        ("dpms_standby", s.dpms_standby.as_secs().to_string()),
+                        ("dpms_suspend", s.dpms_suspend.as_secs().to_string()),
+                        ("dpms_off", s.dpms_off.as_secs().to_string()),
+                    ]),
+                ),
+            ]);
+        }
+        let inhibits = self.inhibits.iter().map(|inhibit| {
+            json_object(vec![
+                ("id", json_string(&inhibit.id)),
+                ("until", json_option(inhibit.until.map(epoch_secs))),
+                (
+                    "pid",
+                    json_option(inhibit.process.as_ref().map(|process| process.pid)),
+                ),
+                ("reason", json_string(&inhibit.reason)),
+            ])
+        });
+        fields.push(("inhibitors", json_array(inhibits)));
+        let logind = self.logind.iter().map(|inhibitor| {
+            json_object(vec![
+                ("who", json_string(&inhibitor.who)),
+                ("why", json_string(&inhibitor.why)),
+                ("pid", inhibitor.pid.to_string()),
+            ])
+        });
+        fields.push(("logind_inhibitors", json_array(logind)));
+        json_object(fields)
+    }
+}
+
+/// e.g.
+/// ```text
+/// Locked for 12m, slept 1 time for 2m
+/// Going to suspend in 1m30s, but inhibited by:
+///   1 (until removed): overnight render
+/// xscreensaver: lock after 10m, password timeout 30s, DPMS off after 10m
+/// ```
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let now = SystemTime::now();
+        let inhibited = !self.inhibits.is_empty() || !self.logind.is_empty();
+        let Some(daemon) = &self.daemon else {
+            writeln!(f, "Not running")?;
+            return write_inhibitors(f, self, "Inhibited by:");
+        };
+        match (daemon.state, daemon.locked_since) {
+            (LockState::Unlocked, _) | (_, None) => writeln!(f, "Unlocked")?,
+            (_, Some(since)) => {
+                let locked_for = now.duration_since(since).unwrap_or_default();
+                write!(f, "Locked for {}", format_duration(locked_for))?;
+                match daemon.sleeps {
+                    0 => writeln!(f)?,
+                    1 => writeln!(f, ", slept once for {}", format_duration(daemon.asleep))?,
+                    n => writeln!(
+                        f,
+                        ", slept {n} times for {}",
+                        format_duration(daemon.asleep)
+                    )?,
+                }
+            }
+        }
+        let next = match (daemon.paused, daemon.state, daemon.next_suspend) {
+            (true, ..) => "Paused, not going to sleep until resumed".into(),
+            (_, LockState::Unlocked, _) => "Nothing to do until the screen locks".into(),
+            (_, _, None) => "Not going to sleep, DPMS is disabled".into(),
+            (_, _, Some(at)) => {
+                let left = at.duration_since(now).unwrap_or_default();
+                format!("Going to {} in {}", daemon.action, format_duration(left))
+            }
+        };
+        match inhibited {
+            true => write_inhibitors(f, self, &format!("{next}, but inhibited by:"))?,
+            false => writeln!(f, "{next}")?,
+        }
+        let s = &daemon.settings;
+        write!(f, "xscreensaver: ")?;
+        match s.lock {
+            true => write!(
+                f,
+                "lock after {}",
+                format_duration(s.timeout + s.lock_timeout)
+            )?,
+            false => write!(f, "blank after {}, no lock", format_duration(s.timeout))?,
+        }
+        write!(
+            f,
+            ", password timeout {}",
+            format_duration(s.password_timeout)
+        )?;
+        match s.dpms_enabled {
+            true => writeln!(f, ", DPMS off after {}", format_duration(s.dpms_off)),
+            false => writeln!(f, ", DPMS disabled"),
+        }
+    }
+}
+
+fn write_inhibitors(f: &mut fmt::Formatter<'_>, status: &Status, heading: &str) -> fmt::Result {
+    if status.inhibits.is_empty() && status.logind.is_empty() {
+        return Ok(());
+    }
+    writeln!(f, "{heading}")?;
+    for inhibit in &status.inhibits {
+        writeln!(f, "  {inhibit}")?;
+    }
+    for inhibitor in &status.logind {
+        writeln!(
+            f,
+            "  logind: {} (pid {}): {}",
+            inhibitor.who, inhibitor.pid, inhibitor.why
+        )?;
+    }
+    Ok(())
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Whole seconds from `from` to `to`, or zero if `to` is earlier
+fn secs_between(from: SystemTime, to: SystemTime) -> u64 {
+    to.duration_since(from).unwrap_or_default().as_secs()
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            '\n' => json += "\\n",
+            c if u32::from(c) < 0x20 => json += &format!("\\u{:04x}", u32::from(c)),
+            c => json.push(c),
+        }
+    }
+    json + "\""
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or("null".into(), |value| value.to_string())
+}
+
+/// An object from keys and values that are already JSON
+fn json_object(fields: Vec<(&str, String)>) -> String {
+    let fields: Vec<String> = fields
+        .into_iter()
+        .map(|(key, value)| format!("{}: {value}", json_string(key)))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn json_array(values: impl Iterator<Item = String>) -> String {
+    format!("[{}]", values.collect::<Vec<_>>().join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daemon() -> DaemonStatus {
+        let locked = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        DaemonStatus {
+            pid: 1234,
+            paused: false,
+            state: LockState::Retrying,
+            locked_since: Some(locked),
+            sleeps: 2,
+            asleep: Duration::from_secs(3600),
+            next_suspend: Some(locked + Duration::from_secs(4000)),
+            action: "hibernate".into(),
+            settings: XscreensaverSettings {
+                timeout: Duration::from_secs(600),
+                lock: true,
+                password_timeout: Duration::from_secs(30),
+                dpms_enabled: true,
+                dpms_off: Duration::from_secs(1200),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn round_trips_through_toml() {
+        let status = daemon();
+        assert_eq!(DaemonStatus::parse(&status.to_toml()), Ok(status));
+    }
+
+    #[test]
+    fn writes_json() {
+        let status = Status {
+            daemon: None,
+            inhibits: vec![Inhibit {
+                id: "backup".into(),
+                until: None,
+                process: None,
+                reason: "say \"hi\"\n".into(),
+            }],
+            logind: Vec::new(),
+        };
+        assert_eq!(
+            status.to_json(),
+            r#"{"running": false, "inhibitors": [{"id": "backup", "until": null, "pid": null, "reason": "say \"hi\"\n"}], "logind_inhibitors": []}"#
+        );
+        let json = Status {
+            daemon: Some(DaemonStatus {
+                next_suspend: None,
+                ..daemon()
+            }),
+            ..status
+        }
+        .to_json();
+        assert!(json.contains(r#""state": "retrying", "locked_since": 1700000000"#));
+        assert!(json.contains(r#""next_suspend": null, "next_suspend_in": null"#));
+        assert!(json.contains(r#""settings": {"timeout": 600, "lock": true"#));
+    }
+}
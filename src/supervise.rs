@@ -0,0 +1,229 @@
+//! Running other programs without leaving them behind: every child is waited for, killed if
+//! it runs for too long, and reaped even if nobody waits for it. What children write to
+//! stderr is logged, prefixed with their name.
+
+use crate::config::format_duration;
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read},
+    process::{self, ChildStdout, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How often to check whether a child has exited
+const POLL: Duration = Duration::from_millis(10);
+/// How long to wait for the rest of a child's output once it's exited, in case something it
+/// started still has the pipe open
+const DRAIN: Duration = Duration::from_millis(100);
+/// Most output kept from each child
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// A running child process, killed and reaped when dropped
+#[derive(Debug)]
+pub struct Child {
+    child: process::Child,
+    stdout: Option<Capture>,
+    stderr: Option<Capture>,
+    reaped: bool,
+}
+
+/// How a child finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exit {
+    pub status: ExitStatus,
+    /// Its stdout, if it was captured with `Child::output`
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Child {
+    /// Start `command`, logging what it writes to stderr
+    pub fn spawn(command: &mut Command) -> io::Result<Self> {
+        let name = command.get_program().to_string_lossy().into_owned();
+        let mut child = command.stderr(Stdio::piped()).spawn()?;
+        let stderr = child
+            .stderr
+            .take()
+            .map(|pipe| Capture::start(pipe, Some(name)));
+        Ok(Child {
+            child,
+            stdout: None,
+            stderr,
+            reaped: false,
+        })
+    }
+
+    /// Run `command` to completion with its stdout captured, like `Command::output`, killing
+    /// it after `timeout`
+    pub fn output(command: &mut Command, timeout: Duration) -> Result<Exit, Error> {
+        let mut child = Child::spawn(command.stdout(Stdio::piped()))?;
+        child.stdout = child.stdout().map(|pipe| Capture::start(pipe, None));
+        child.wait(Some(timeout))
+    }
+
+    /// Take its stdout, if it was piped
+    pub fn stdout(&mut self) -> Option<ChildStdout> {
+        self.child.stdout.take()
+    }
+
+    /// Wait for it to exit, or with a timeout kill it if it's still running after that long
+    pub fn wait(mut self, timeout: Option<Duration>) -> Result<Exit, Error> {
+        let status = match timeout {
+            None => self.child.wait()?,
+            Some(timeout) => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    if let Some(status) = self.child.try_wait()? {
+                        break status;
+                    }
+                    if Instant::now() >= deadline {
+                        self.kill();
+                        return Err(Error::TimedOut(timeout));
+                    }
+                    thread::sleep(POLL);
+                }
+            }
+        };
+        self.reaped = true;
+        let finish = |capture: Option<Capture>| capture.map(Capture::finish).unwrap_or_default();
+        Ok(Exit {
+            status,
+            stdout: finish(self.stdout.take()),
+            stderr: finish(self.stderr.take()),
+        })
+    }
+
+    /// Kill it if it's still running, and reap it
+    pub fn kill(&mut self) {
+        if !self.reaped {
+            // It may have just exited
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            self.reaped = true;
+        }
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Output being read from a child in the background
+#[derive(Debug)]
+struct Capture {
+    text: Arc<Mutex<String>>,
+    reader: JoinHandle<()>,
+}
+
+impl Capture {
+    /// Read `pipe` to the end, logging each line prefixed with `name` if there is one
+    fn start(pipe: impl Read + Send + 'static, name: Option<String>) -> Self {
+        let text = Arc::new(Mutex::new(String::new()));
+        let shared = text.clone();
+        let reader = thread::spawn(move || {
+            for line in BufReader::new(pipe).split(b'\n') {
+                let Ok(line) = line else {
+                    return;
+                };
+                let line = String::from_utf8_lossy(&line);
+                if let Some(name) = &name {
+                    eprintln!("{name}: {line}");
+                }
+                let mut text = lock(&shared);
+                if text.len() + line.len() < MAX_OUTPUT {
+                    text.push_str(&line);
+                    text.push('\n');
+                }
+            }
+        });
+        Capture { text, reader }
+    }
+
+    /// Everything read, once it's all been read or it's been long enough
+    fn finish(self) -> String {
+        let deadline = Instant::now() + DRAIN;
+        while !self.reader.is_finished() && Instant::now() < deadline {
+            thread::sleep(POLL);
+        }
+        std::mem::take(&mut lock(&self.text))
+    }
+}
+
+fn lock(text: &Mutex<String>) -> MutexGuard<'_, String> {
+    text.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Why a child didn't finish
+#[derive(Debug)]
+pub enum Error {
+    /// It couldn't be started or waited for
+    Io(io::Error),
+    /// It ran for longer than this, and was killed
+    TimedOut(Duration),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::TimedOut(timeout) => {
+                write!(f, "killed after running for {}", format_duration(*timeout))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("/bin/sh");
+        command.args(["-c", script]);
+        command
+    }
+
+    #[test]
+    fn captures_output() {
+        let exit = Child::output(
+            &mut sh("echo out; echo err >&2; exit 3"),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(exit.status.code(), Some(3));
+        assert_eq!(exit.stdout, "out\n");
+        assert_eq!(exit.stderr, "err\n");
+    }
+
+    #[test]
+    fn kills_children_that_take_too_long() {
+        let started = Instant::now();
+        assert!(matches!(
+            Child::output(&mut sh("exec sleep 10"), Duration::from_millis(200)),
+            Err(Error::TimedOut(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn reaps_children_nobody_waits_for() {
+        let child = Child::spawn(&mut sh("exec sleep 10")).unwrap();
+        let proc = format!("/proc/{}", child.child.id());
+        assert!(Path::new(&proc).exists());
+        drop(child);
+        assert!(!Path::new(&proc).exists());
+    }
+}
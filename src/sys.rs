@@ -0,0 +1,150 @@
+//! Thin wrappers around the libc calls std doesn't expose
+
+use std::{
+    ffi::{CString, OsString},
+    fs::File,
+    io::{self, Read},
+    os::{
+        fd::FromRawFd,
+        raw::{c_char, c_int, c_long},
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::Path,
+    time::Duration,
+};
+
+pub const SIGHUP: c_int = 1;
+const SIG_BLOCK: c_int = 0;
+
+/// File opened for writing was closed
+pub const IN_CLOSE_WRITE: u32 = 0x8;
+/// File was moved into the watched directory
+pub const IN_MOVED_TO: u32 = 0x80;
+const IN_CLOEXEC: c_int = 0o2000000;
+
+/// Stops while the system is asleep, like `Instant`
+pub const CLOCK_MONOTONIC: c_int = 1;
+/// Like CLOCK_MONOTONIC, but includes time spent asleep
+pub const CLOCK_BOOTTIME: c_int = 7;
+
+// time_t is a long everywhere but x32, where it's 64 bits with 32-bit longs
+#[cfg(all(target_arch = "x86_64", target_pointer_width = "32"))]
+compile_error!("x32 isn't supported");
+#[allow(non_camel_case_types)]
+type time_t = c_long;
+
+#[repr(C)]
+struct Timespec {
+    tv_sec: time_t,
+    tv_nsec: c_long,
+}
+
+/// glibc's sigset_t
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SigSet([u64; 16]);
+
+extern "C" {
+    fn getuid() -> u32;
+    fn clock_gettime(clock: c_int, tp: *mut Timespec) -> c_int;
+    fn sigemptyset(set: *mut SigSet) -> c_int;
+    fn sigaddset(set: *mut SigSet, signum: c_int) -> c_int;
+    fn pthread_sigmask(how: c_int, set: *const SigSet, old: *mut SigSet) -> c_int;
+    fn sigwait(set: *const SigSet, sig: *mut c_int) -> c_int;
+    fn inotify_init1(flags: c_int) -> c_int;
+    fn inotify_add_watch(fd: c_int, path: *const c_char, mask: u32) -> c_int;
+}
+
+/// Our real user ID
+pub fn uid() -> u32 {
+    unsafe { getuid() }
+}
+
+/// Time since an unspecified starting point on one of the CLOCK_* clocks
+pub fn clock(clock: c_int) -> io::Result<Duration> {
+    let mut time = Timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    match unsafe { clock_gettime(clock, &mut time) } {
+        0 => Ok(Duration::new(time.tv_sec as u64, time.tv_nsec as u32)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Signals blocked so they can be waited for instead of handled asynchronously
+pub struct Signals(SigSet);
+
+impl Signals {
+    /// Block `signals` in this thread. Threads spawned afterwards inherit the mask,
+    /// so call this before starting any.
+    pub fn block(signals: &[c_int]) -> io::Result<Self> {
+        let mut set = SigSet([0; 16]);
+        unsafe {
+            sigemptyset(&mut set);
+            for signal in signals {
+                if sigaddset(&mut set, *signal) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            match pthread_sigmask(SIG_BLOCK, &set, std::ptr::null_mut()) {
+                0 => Ok(Signals(set)),
+                e => Err(io::Error::from_raw_os_error(e)),
+            }
+        }
+    }
+
+    /// Wait for one of the signals, returning which
+    pub fn wait(&self) -> io::Result<c_int> {
+        let mut signal = 0;
+        match unsafe { sigwait(&self.0, &mut signal) } {
+            0 => Ok(signal),
+            e => Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// Filesystem change notifications
+pub struct Inotify {
+    file: File,
+}
+
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        match unsafe { inotify_init1(IN_CLOEXEC) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Inotify {
+                file: unsafe { File::from_raw_fd(fd) },
+            }),
+        }
+    }
+
+    /// Watch a file or directory for the events in `mask`
+    pub fn add_watch(&self, path: &Path, mask: u32) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        match unsafe { inotify_add_watch(self.file.as_raw_fd(), path.as_ptr(), mask) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait for events, returning the names of the files in watched directories they
+    /// happened to. Events on watched files themselves have empty names.
+    pub fn read_names(&mut self) -> io::Result<Vec<OsString>> {
+        // Room for plenty of events with names up to NAME_MAX
+        let mut buf = vec![0; 64 * 1024];
+        let len = self.file.read(&mut buf)?;
+        let mut names = Vec::new();
+        let mut event = &buf[..len];
+        // struct inotify_event { int wd; uint32_t mask; uint32_t cookie; uint32_t len; char name[]; }
+        while event.len() >= 16 {
+            let name_len = u32::from_ne_bytes(event[12..16].try_into().unwrap()) as usize;
+            let name = event.get(16..16 + name_len).unwrap_or_default();
+            let name = name.split(|b| *b == 0).next().unwrap_or_default();
+            names.push(OsString::from_vec(name.to_vec()));
+            event = event.get(16 + name_len..).unwrap_or_default();
+        }
+        Ok(names)
+    }
+}
@@ -0,0 +1,341 @@
+//! Just enough TOML for our config file: `key = value` pairs under optional
+//! `[table]` headers, with string, integer, boolean and array values
+
+use std::{collections::BTreeMap, fmt};
+
+/// A parsed value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Name of the type, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+        }
+    }
+}
+
+/// Writes the value as TOML, the inverse of `parse_value`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        c => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Value::Integer(n) => write!(f, "{n}"),
+            Value::Boolean(b) => write!(f, "{b}"),
+            Value::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Keys, including any `table.` prefix, mapped to the line they were on and their value
+pub type Document = BTreeMap<String, (usize, Value)>;
+
+/// Parse a document
+pub fn parse(text: &str) -> Result<Document, Error> {
+    let mut document = Document::new();
+    let mut table = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let error = |message: &str| Error {
+            line: line_number,
+            message: message.into(),
+        };
+        let mut parser = Parser {
+            rest: line.trim_start(),
+        };
+        if parser.at_end() {
+            continue;
+        }
+        if parser.eat('[') {
+            let name = parser.key().ok_or_else(|| error("expected a table name"))?;
+            if !parser.eat(']') || !parser.at_end() {
+                return Err(error("expected ']' after the table name"));
+            }
+            table = format!("{name}.");
+            continue;
+        }
+        let key = parser.key().ok_or_else(|| error("expected a key"))?;
+        if !parser.eat('=') {
+            return Err(error("expected '=' after the key"));
+        }
+        let value = parser.value().map_err(|message| error(&message))?;
+        if !parser.at_end() {
+            return Err(error("unexpected text after the value"));
+        }
+        let key = format!("{table}{key}");
+        if document.contains_key(&key) {
+            return Err(error(&format!("duplicate key {key:?}")));
+        }
+        document.insert(key, (line_number, value));
+    }
+    Ok(document)
+}
+
+/// Parse a single value such as `3`, `"text"` or `["a", "b"]`
+pub fn parse_value(text: &str) -> Option<Value> {
+    let mut parser = Parser { rest: text };
+    let value = parser.value().ok()?;
+    parser.at_end().then_some(value)
+}
+
+/// Parses one line
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl Parser<'_> {
+    fn skip_space(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    /// Nothing but whitespace or a comment left
+    fn at_end(&mut self) -> bool {
+        self.skip_space();
+        self.rest.is_empty() || self.rest.starts_with('#')
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A bare key, letters, digits, `_` and `-`
+    fn key(&mut self) -> Option<String> {
+        self.skip_space();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return None;
+        }
+        let (key, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(key.into())
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_space();
+        if self.eat('"') {
+            return self.basic_string().map(Value::String);
+        }
+        if self.eat('\'') {
+            let (string, rest) = self.rest.split_once('\'').ok_or("unterminated string")?;
+            self.rest = rest;
+            return Ok(Value::String(string.into()));
+        }
+        if self.eat('[') {
+            let mut items = Vec::new();
+            while !self.eat(']') {
+                if self.rest.is_empty() {
+                    return Err("arrays must be on one line".into());
+                }
+                items.push(self.value()?);
+                if !self.eat(',') && !self.rest.trim_start().starts_with(']') {
+                    return Err("expected ',' or ']' in array".into());
+                }
+            }
+            return Ok(Value::Array(items));
+        }
+        let end = self
+            .rest
+            .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+            .unwrap_or(self.rest.len());
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        match word {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            "" => Err("expected a value".into()),
+            number => number
+                .replace('_', "")
+                .parse()
+                .map(Value::Integer)
+                .map_err(|_| format!("invalid value {number:?}")),
+        }
+    }
+
+    /// The rest of a `"` string, handling escapes
+    fn basic_string(&mut self) -> Result<String, String> {
+        let mut string = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(string);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some(c) => return Err(format!("unsupported escape \\{c}")),
+                    None => break,
+                },
+                c => string.push(c),
+            }
+        }
+        Err("unterminated string".into())
+    }
+}
+
+/// Where and why a document couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Value {
+        parse(&format!("key = {text}")).unwrap()["key"].1.clone()
+    }
+
+    fn error(text: &str) -> Error {
+        parse(text).unwrap_err()
+    }
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(value("\"text\""), Value::String("text".into()));
+        assert_eq!(value("'C:\\raw'"), Value::String("C:\\raw".into()));
+        assert_eq!(value("-3"), Value::Integer(-3));
+        assert_eq!(value("1_000"), Value::Integer(1000));
+        assert_eq!(value("true"), Value::Boolean(true));
+        assert_eq!(value("false"), Value::Boolean(false));
+        assert_eq!(
+            value("[\"a\", 2, [true],]"),
+            Value::Array(vec![
+                Value::String("a".into()),
+                Value::Integer(2),
+                Value::Array(vec![Value::Boolean(true)]),
+            ])
+        );
+        assert_eq!(value("[ ]"), Value::Array(vec![]));
+    }
+
+    #[test]
+    fn processes_escapes() {
+        assert_eq!(
+            value(r#""a\"b\\c\nd\te""#),
+            Value::String("a\"b\\c\nd\te".into())
+        );
+        // Round trips through Display
+        let text = Value::String("quote \" backslash \\ newline \n tab \t".into());
+        assert_eq!(parse_value(&text.to_string()), Some(text));
+    }
+
+    #[test]
+    fn skips_comments_and_prefixes_tables() {
+        let document = parse(
+            "# settings\n\
+             \n\
+             action = \"hibernate\"  # deeper\n\
+             [escalate]\n\
+             after = \"12h\" # a comment with \"quotes\"\n\
+             path = \"/opt/a #b\"\n",
+        )
+        .unwrap();
+        let keys: Vec<(&str, usize)> = document
+            .iter()
+            .map(|(key, (line, _))| (key.as_str(), *line))
+            .collect();
+        assert_eq!(
+            keys,
+            [("action", 3), ("escalate.after", 5), ("escalate.path", 6)]
+        );
+        assert_eq!(
+            document["escalate.path"].1,
+            Value::String("/opt/a #b".into())
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        let cases = [
+            ("a = 1\nb 2\n", 2, "expected '=' after the key"),
+            ("= 1\n", 1, "expected a key"),
+            ("a = \n", 1, "expected a value"),
+            ("a = \"open\n", 1, "unterminated string"),
+            ("a = 'open\n", 1, "unterminated string"),
+            ("a = \"\\x\"\n", 1, "unsupported escape \\x"),
+            ("a = [1,\n2]\n", 1, "arrays must be on one line"),
+            ("a = [1 2]\n", 1, "expected ',' or ']' in array"),
+            ("a = 1 2\n", 1, "unexpected text after the value"),
+            ("a = one\n", 1, "invalid value \"one\""),
+            ("a = 1\n\na = 2\n", 3, "duplicate key \"a\""),
+            ("[]\n", 1, "expected a table name"),
+            ("[table\n", 1, "expected ']' after the table name"),
+        ];
+        for (text, line, message) in cases {
+            assert_eq!(
+                error(text),
+                Error {
+                    line,
+                    message: message.into(),
+                },
+                "{text:?}"
+            );
+        }
+        assert_eq!(error("x").to_string(), "line 1: expected '=' after the key");
+    }
+
+    #[test]
+    fn parses_single_values() {
+        assert_eq!(parse_value("3"), Some(Value::Integer(3)));
+        assert_eq!(
+            parse_value("[\"a\"]  "),
+            Some(Value::Array(vec![Value::String("a".into())]))
+        );
+        assert_eq!(parse_value("suspend"), None);
+        assert_eq!(parse_value("1 2"), None);
+    }
+}
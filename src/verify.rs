@@ -0,0 +1,201 @@
+//! Checking the system really went to sleep.
+//!
+//! Asking logind or systemctl to sleep returns as soon as the request is accepted, before
+//! anything happens, so a sleep that fails in the kernel or is refused along the way looks
+//! the same as one that worked. Instead, watch for time passing on CLOCK_BOOTTIME but not on
+//! CLOCK_MONOTONIC, and for the kernel's counts in `/sys/power/suspend_stats` changing.
+
+use crate::{clock::Clock, config::format_duration, machine::MIN_SLEEP};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often to check whether it's slept
+const POLL: Duration = Duration::from_millis(100);
+
+/// The kernel's counts of suspend attempts, since booting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuspendStats {
+    pub success: u64,
+    pub fail: u64,
+    /// Where the last failure happened, e.g. `suspend` or `freeze`
+    pub last_failed_step: Option<String>,
+    /// The device that failed to suspend last, if it was a device
+    pub last_failed_dev: Option<String>,
+}
+
+impl SuspendStats {
+    /// Read `<sysfs>/power/suspend_stats`, or None if the kernel doesn't have it
+    pub fn read(sysfs: &Path) -> Option<Self> {
+        let dir = sysfs.join("power/suspend_stats");
+        let read = |name| {
+            fs::read_to_string(dir.join(name))
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+        Some(SuspendStats {
+            success: read("success")?.parse().ok()?,
+            fail: read("fail")?.parse().ok()?,
+            last_failed_step: read("last_failed_step"),
+            last_failed_dev: read("last_failed_dev"),
+        })
+    }
+}
+
+/// The clocks and counts from before asking to sleep, to compare with afterwards
+#[derive(Debug, Clone)]
+pub struct Baseline {
+    sysfs: PathBuf,
+    asleep: Duration,
+    stats: Option<SuspendStats>,
+}
+
+impl Baseline {
+    /// Take a baseline, reading suspend_stats under `sysfs`, usually `/sys`
+    pub fn take(clock: &impl Clock, sysfs: &Path) -> Self {
+        Baseline {
+            sysfs: sysfs.into(),
+            asleep: clock.asleep(),
+            stats: SuspendStats::read(sysfs),
+        }
+    }
+
+    /// How long it's slept for since the baseline, or None if it hasn't yet
+    pub fn check(&self, clock: &impl Clock) -> Option<Result<Duration, Error>> {
+        let asleep = clock.asleep().saturating_sub(self.asleep);
+        if asleep >= MIN_SLEEP {
+            return Some(Ok(asleep));
+        }
+        let (before, after) = (self.stats.as_ref()?, SuspendStats::read(&self.sysfs)?);
+        if after.success > before.success {
+            // A quick sleep, maybe only to freeze
+            Some(Ok(asleep))
+        } else if after.fail > before.fail {
+            Some(Err(Error::Failed {
+                step: after.last_failed_step,
+                device: after.last_failed_dev,
+            }))
+        } else {
+            None
+        }
+    }
+
+    /// Wait up to `timeout` for it to have slept, returning how long for
+    pub fn wait(&self, clock: &impl Clock, timeout: Duration) -> Result<Duration, Error> {
+        // Instant stops while asleep, but by then it's slept
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(result) = self.check(clock) {
+                return result;
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::TimedOut(timeout));
+            }
+            thread::sleep(POLL);
+        }
+    }
+}
+
+/// Why it didn't sleep
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The kernel tried to suspend, but failed
+    Failed {
+        step: Option<String>,
+        device: Option<String>,
+    },
+    /// Nothing happened for this long
+    TimedOut(Duration),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Failed { step, device } => {
+                write!(f, "the kernel failed to suspend")?;
+                if let Some(step) = step {
+                    write!(f, " at {step}")?;
+                }
+                if let Some(device) = device {
+                    write!(f, " because of {device}")?;
+                }
+                Ok(())
+            }
+            Error::TimedOut(timeout) => {
+                write!(f, "still awake after {}", format_duration(*timeout))
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::test_clock::TestClock, test_dir::TempDir};
+
+    /// A fake sysfs
+    fn fake_sysfs() -> TempDir {
+        let dir = TempDir::new("sysfs");
+        fs::create_dir_all(dir.path().join("power/suspend_stats")).unwrap();
+        dir
+    }
+
+    fn stats(sysfs: &TempDir, success: u64, fail: u64, step: &str, dev: &str) {
+        let dir = sysfs.path().join("power/suspend_stats");
+        fs::write(dir.join("success"), format!("{success}\n")).unwrap();
+        fs::write(dir.join("fail"), format!("{fail}\n")).unwrap();
+        fs::write(dir.join("last_failed_step"), format!("{step}\n")).unwrap();
+        fs::write(dir.join("last_failed_dev"), format!("{dev}\n")).unwrap();
+    }
+
+    #[test]
+    fn notices_time_asleep() {
+        let clock = TestClock::new();
+        let sysfs = fake_sysfs();
+        let baseline = Baseline::take(&clock, sysfs.path());
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(baseline.check(&clock), None);
+        clock.sleep(Duration::from_secs(60));
+        assert_eq!(
+            baseline.wait(&clock, Duration::from_secs(5)),
+            Ok(Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn reads_suspend_stats() {
+        let clock = TestClock::new();
+        let sysfs = fake_sysfs();
+        stats(&sysfs, 3, 1, "", "");
+        let baseline = Baseline::take(&clock, sysfs.path());
+        assert_eq!(baseline.check(&clock), None);
+        stats(&sysfs, 3, 2, "suspend", "0000:00:14.0");
+        assert_eq!(
+            baseline.check(&clock),
+            Some(Err(Error::Failed {
+                step: Some("suspend".into()),
+                device: Some("0000:00:14.0".into()),
+            }))
+        );
+        // Resumed too quickly to tell from the clocks
+        stats(&sysfs, 4, 2, "", "");
+        assert_eq!(baseline.check(&clock), Some(Ok(Duration::ZERO)));
+    }
+
+    #[test]
+    fn gives_up_waiting() {
+        let clock = TestClock::new();
+        let sysfs = fake_sysfs();
+        let baseline = Baseline::take(&clock, sysfs.path());
+        assert_eq!(
+            baseline.wait(&clock, Duration::from_millis(200)),
+            Err(Error::TimedOut(Duration::from_millis(200)))
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Following `xscreensaver-command -watch`, restarting it whenever it exits
+
+use crate::{event::Event, supervise::Child};
+use std::{
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Longest to wait for `xscreensaver-command -time`
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First delay before restarting a watcher that exited
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between restarts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A watcher that ran this long resets the backoff
+const STABLE: Duration = Duration::from_secs(60);
+
+/// Messages from the watcher thread
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// An event from xscreensaver
+    Event(Event),
+    /// The watcher (re)started, so earlier state can't be trusted.
+    /// Carries what `xscreensaver-command -time` reported, if anything.
+    Resync(Option<ScreenState>),
+}
+
+/// Screen state reported by `xscreensaver-command -time`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenState {
+    Unblanked,
+    Blanked,
+    Locked,
+}
+
+impl ScreenState {
+    /// Parse `XScreenSaver 6.01: screen locked since Fri Nov 13 17:39:59 2020`
+    fn parse(output: &str) -> Option<Self> {
+        let (_, status) = output.split_once(": screen ")?;
+        match status.split_whitespace().next()? {
+            "non-blanked" => Some(ScreenState::Unblanked),
+            "blanked" => Some(ScreenState::Blanked),
+            "locked" => Some(ScreenState::Locked),
+            _ => None,
+        }
+    }
+
+    /// Ask xscreensaver for the current screen state
+    fn query(xscreensaver_command: &Path) -> Option<Self> {
+        let output = Child::output(
+            Command::new(xscreensaver_command).arg("-time"),
+            QUERY_TIMEOUT,
+        )
+        .map_err(|e| eprintln!("Querying xscreensaver state: {e}"))
+        .ok()?;
+        let state = ScreenState::parse(&output.stdout);
+        if state.is_none() {
+            eprintln!("Unrecognised xscreensaver-command -time output");
+        }
+        state
+    }
+}
+
+/// Watch Xscreensaver output for events, restarting the watcher whenever it exits
+pub fn spawn_xscreensaver_watch<T>(xscreensaver_command: PathBuf, tx: Sender<T>)
+where
+    T: From<WatchEvent> + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let started = Instant::now();
+            if !watch(&xscreensaver_command, &tx) {
+                return;
+            }
+            if started.elapsed() > STABLE {
+                backoff = MIN_BACKOFF;
+            }
+            eprintln!("Restarting xscreensaver-command in {}s", backoff.as_secs());
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
+
+/// Run one `xscreensaver-command -watch` until it exits.
+/// Returns false once the receiver has gone away.
+fn watch<T: From<WatchEvent>>(xscreensaver_command: &Path, tx: &Sender<T>) -> bool {
+    let mut xs = match Child::spawn(
+        Command::new(xscreensaver_command)
+            .arg("-watch")
+            .stdout(Stdio::piped()),
+    ) {
+        Ok(xs) => xs,
+        Err(e) => {
+            eprintln!("Running xscreensaver-command: {e}");
+            return true;
+        }
+    };
+    let Some(stdout) = xs.stdout() else {
+        eprintln!("xscreensaver-command has no stdout");
+        return true;
+    };
+
+    let mut connected = tx
+        .send(WatchEvent::Resync(ScreenState::query(xscreensaver_command)).into())
+        .is_ok();
+    let mut lines = BufReader::new(stdout).lines();
+    while connected {
+        match lines.next() {
+            Some(Ok(line)) => match line.parse() {
+                Ok(event) => connected = tx.send(WatchEvent::Event(event).into()).is_ok(),
+                Err(e) => eprintln!("Ignoring xscreensaver event: {e}"),
+            },
+            Some(Err(e)) => {
+                eprintln!("Reading xscreensaver-command: {e}");
+                break;
+            }
+            None => break,
+        }
+    }
+
+    if !connected {
+        // Killed and reaped when dropped
+        return false;
+    }
+    match xs.wait(None) {
+        Ok(exit) => eprintln!("xscreensaver-command exited: {}", exit.status),
+        Err(e) => eprintln!("Waiting for xscreensaver-command: {e}"),
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event::Timestamp, test_dir::TempDir};
+    use std::{fs, os::unix::fs::PermissionsExt, sync::mpsc};
+
+    const AT: &str = "Fri Nov 13 17:39:59 2020";
+
+    /// An xscreensaver-command that reports the screen locked and prints these events
+    fn xscreensaver_command(dir: &TempDir, events: &str) -> PathBuf {
+        let path = dir.path().join("xscreensaver-command");
+        let script = format!(
+            "#!/bin/sh\n\
+             case \"$1\" in\n\
+             -time) echo 'XScreenSaver 6.01: screen locked since {AT}' ;;\n\
+             -watch) printf '{events}' ;;\n\
+             esac\n"
+        );
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_screen_states() {
+        let parse =
+            |state: &str| ScreenState::parse(&format!("XScreenSaver 6.01: screen {state}\n"));
+        assert_eq!(
+            parse(&format!("non-blanked since {AT}")),
+            Some(ScreenState::Unblanked)
+        );
+        assert_eq!(
+            parse(&format!("blanked since {AT}")),
+            Some(ScreenState::Blanked)
+        );
+        assert_eq!(
+            parse(&format!("locked since {AT}")),
+            Some(ScreenState::Locked)
+        );
+        assert_eq!(parse("melted"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(
+            ScreenState::parse("xscreensaver-command: no screensaver is running"),
+            None
+        );
+        assert_eq!(ScreenState::parse(""), None);
+    }
+
+    #[test]
+    fn forwards_events_and_skips_garbage() {
+        let dir = TempDir::new("watch");
+        let events = format!(
+            "BLANK {AT}\\nLOCK {AT}\\nnonsense\\nRUN 34\\nBLANK yesterday\\nUNBLANK {AT}\\n"
+        );
+        let command = xscreensaver_command(&dir, &events);
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        assert!(watch(&command, &tx));
+        let at: Timestamp = AT.parse().unwrap();
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                WatchEvent::Resync(Some(ScreenState::Locked)),
+                WatchEvent::Event(Event::Blank(at)),
+                WatchEvent::Event(Event::Lock(at)),
+                WatchEvent::Event(Event::Run { hack: 34 }),
+                WatchEvent::Event(Event::Unblank(at)),
+            ]
+        );
+
+        // Stops once nothing's listening
+        drop(rx);
+        assert!(!watch(&command, &tx));
+    }
+
+    #[test]
+    fn restarts_and_resyncs() {
+        let dir = TempDir::new("watch");
+        let command = xscreensaver_command(&dir, &format!("LOCK {AT}\\n"));
+        let (tx, rx) = mpsc::channel::<WatchEvent>();
+        spawn_xscreensaver_watch(command, tx);
+        let next = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let resync = WatchEvent::Resync(Some(ScreenState::Locked));
+        let lock = WatchEvent::Event(Event::Lock(AT.parse().unwrap()));
+        assert_eq!([next(), next()], [resync.clone(), lock.clone()]);
+        // After MIN_BACKOFF
+        assert_eq!([next(), next()], [resync, lock]);
+    }
+}
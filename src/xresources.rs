@@ -0,0 +1,266 @@
+//! X resource files, the format of ~/.xscreensaver, app-defaults and `xrdb -query`
+
+use std::fmt;
+
+/// One `name: value` entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    /// Line the entry started on
+    pub line: usize,
+    /// The full resource specification, e.g. `XScreenSaver.dpmsOff` or `*dpmsOff`
+    pub name: String,
+    /// The value with escapes processed and surrounding whitespace removed
+    pub value: String,
+}
+
+impl Resource {
+    /// The name of a setting that applies to the whole application, without any
+    /// `XScreenSaver.`, `xscreensaver.` or `*` prefix. None for resources of
+    /// other applications or of individual widgets, e.g. `XScreenSaver.Dialog.font`.
+    pub fn setting(&self, class: &str) -> Option<&str> {
+        let name = self.name.as_str();
+        let name = name
+            .strip_prefix(class)
+            .or_else(|| name.strip_prefix(class.to_lowercase().as_str()))
+            .filter(|rest| rest.starts_with(['.', '*']))
+            .unwrap_or(name);
+        let name = name.trim_start_matches(['.', '*']);
+        (!name.is_empty() && !name.contains(['.', '*', '?'])).then_some(name)
+    }
+}
+
+/// Parse the text of a resource file
+pub fn parse(text: &str) -> Result<Vec<Resource>, ParseError> {
+    let mut resources = Vec::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line_number = i + 1;
+        // Join continuation lines
+        let mut entry = line.to_string();
+        while ends_with_continuation(&entry) {
+            entry.pop();
+            match lines.next() {
+                Some((_, next)) => entry.push_str(next),
+                None => break,
+            }
+        }
+
+        let entry = entry.trim_start();
+        // `!` comments are standard, `#` is cpp for xrdb and comments in ~/.xscreensaver
+        if entry.is_empty() || entry.starts_with(['!', '#']) {
+            continue;
+        }
+        let error = |kind| ParseError {
+            line: line_number,
+            kind,
+        };
+        let (name, value) = entry
+            .split_once(':')
+            .ok_or_else(|| error(ErrorKind::MissingColon))?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(error(ErrorKind::InvalidName(name.into())));
+        }
+        resources.push(Resource {
+            line: line_number,
+            name: name.into(),
+            value: unescape(value.trim_start_matches([' ', '\t'])),
+        });
+    }
+    Ok(resources)
+}
+
+/// Does the line end in an unescaped backslash
+fn ends_with_continuation(line: &str) -> bool {
+    line.bytes().rev().take_while(|b| *b == b'\\').count() % 2 == 1
+}
+
+/// Process `\n`, `\\`, `\ ` and `\nnn` octal escapes, and drop trailing whitespace
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    // Bytes up to here can't be trimmed as trailing whitespace
+    let mut keep = 0;
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            if !c.is_whitespace() {
+                keep = unescaped.len();
+            }
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(d @ '0'..='7') => {
+                let mut code = d.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|d| d.to_digit(8)) {
+                        Some(digit) => {
+                            code = code * 8 + digit;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                unescaped.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+            }
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+        keep = unescaped.len();
+    }
+    unescaped.truncate(keep);
+    unescaped
+}
+
+/// Where and why a resource file couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub kind: ErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Not a comment, but there's no `:` between name and value
+    MissingColon,
+    /// The resource name is empty or has spaces in it
+    InvalidName(String),
+    /// A boolean wasn't true/false, on/off or yes/no
+    InvalidBool { name: String, value: String },
+    /// A time wasn't `seconds`, `minutes:seconds` or `hours:minutes:seconds`
+    InvalidTime { name: String, value: String },
+    /// A number didn't parse, or was out of range for the setting
+    InvalidNumber { name: String, value: String },
+    /// A value wasn't one of the allowed choices
+    InvalidChoice { name: String, value: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ErrorKind::MissingColon => write!(f, "expected `name: value`"),
+            ErrorKind::InvalidName(name) => write!(f, "invalid resource name {name:?}"),
+            ErrorKind::InvalidBool { name, value } => {
+                write!(f, "{name} should be True or False, not {value:?}")
+            }
+            ErrorKind::InvalidTime { name, value } => {
+                write!(f, "{name} should be a time such as 0:10:00, not {value:?}")
+            }
+            ErrorKind::InvalidNumber { name, value } => {
+                write!(f, "{name} should be a number, not {value:?}")
+            }
+            ErrorKind::InvalidChoice { name, value } => {
+                write!(f, "{name} can't be {value:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(text: &str) -> Vec<(usize, String, String)> {
+        parse(text)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.line, r.name, r.value))
+            .collect()
+    }
+
+    fn resource(name: &str) -> Resource {
+        Resource {
+            line: 1,
+            name: name.into(),
+            value: String::new(),
+        }
+    }
+
+    #[test]
+    fn skips_comments_and_blank_lines() {
+        let text = "! xscreensaver settings\n\n# written by the demo\n  *lock:  True  \n";
+        assert_eq!(values(text), vec![(4, "*lock".into(), "True".into())]);
+    }
+
+    #[test]
+    fn joins_continuation_lines() {
+        let text = "*programs: \\\n  xmatrix -root \\n\\\n  gears\n*lock: False\n";
+        assert_eq!(
+            values(text),
+            vec![
+                (1, "*programs".into(), "xmatrix -root \n  gears".into()),
+                (4, "*lock".into(), "False".into()),
+            ]
+        );
+        // An escaped backslash doesn't continue the line
+        assert_eq!(
+            values("*textLiteral: a\\\\\n*lock: True\n"),
+            vec![
+                (1, "*textLiteral".into(), "a\\".into()),
+                (2, "*lock".into(), "True".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn processes_escapes() {
+        assert_eq!(unescape("a\\101b"), "aAb");
+        assert_eq!(unescape("\\0610"), "10");
+        assert_eq!(unescape("one\\ntwo"), "one\ntwo");
+        // Escaped trailing whitespace is kept, the rest dropped
+        assert_eq!(unescape("x\\  \t"), "x ");
+        assert_eq!(unescape("end\\"), "end\\");
+    }
+
+    #[test]
+    fn finds_settings_for_the_class() {
+        assert_eq!(
+            resource("XScreenSaver.dpmsOff").setting("XScreenSaver"),
+            Some("dpmsOff")
+        );
+        assert_eq!(
+            resource("xscreensaver*lock").setting("XScreenSaver"),
+            Some("lock")
+        );
+        assert_eq!(
+            resource("*timeout").setting("XScreenSaver"),
+            Some("timeout")
+        );
+        assert_eq!(
+            resource(".timeout").setting("XScreenSaver"),
+            Some("timeout")
+        );
+        assert_eq!(resource("timeout").setting("XScreenSaver"), Some("timeout"));
+        assert_eq!(
+            resource("XScreenSaver.Dialog.font").setting("XScreenSaver"),
+            None
+        );
+        assert_eq!(resource("*Dialog.font").setting("XScreenSaver"), None);
+        assert_eq!(resource("Emacs.font").setting("XScreenSaver"), None);
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(
+            parse("*lock: True\nno colon here\n"),
+            Err(ParseError {
+                line: 2,
+                kind: ErrorKind::MissingColon,
+            })
+        );
+        assert_eq!(
+            parse("\n\nbad name: x\n").unwrap_err(),
+            ParseError {
+                line: 3,
+                kind: ErrorKind::InvalidName("bad name".into()),
+            }
+        );
+        let error = parse(": x\n").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::InvalidName(String::new()));
+        assert_eq!(error.to_string(), "line 1: invalid resource name \"\"");
+    }
+}